@@ -0,0 +1,52 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+use crate::storage::is_s3;
+
+/// Kinds of external files a config can reference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssetKind {
+    /// `Operation::Text::font`
+    Font,
+    /// `Operation::Overlay::image`
+    Overlay,
+    /// `FilterOperation::Lut::lut`
+    Lut,
+}
+
+/// Maps asset references from a config to files on disk.
+pub trait AssetResolver: Send + Sync {
+    fn resolve(&self, kind: AssetKind, reference: &Path) -> Result<PathBuf>;
+}
+
+/// The default resolver: fonts are looked up in `assets/fonts/` unless
+/// they are `s3://` locations, everything else is used as a plain path.
+pub struct LocalAssets;
+
+impl AssetResolver for LocalAssets {
+    fn resolve(&self, kind: AssetKind, reference: &Path) -> Result<PathBuf> {
+        Ok(match kind {
+            AssetKind::Font if !is_s3(reference) => Path::new("assets/fonts/").join(reference),
+            AssetKind::Font => reference.to_path_buf(),
+            AssetKind::Overlay | AssetKind::Lut => reference.to_path_buf(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_local_fonts_in_the_fonts_directory() {
+        let resolve = |reference| LocalAssets.resolve(AssetKind::Font, Path::new(reference));
+        assert_eq!(
+            resolve("Roboto.ttf").unwrap(),
+            Path::new("assets/fonts/Roboto.ttf")
+        );
+        assert_eq!(
+            resolve("s3://bucket/fonts/Roboto.ttf").unwrap(),
+            Path::new("s3://bucket/fonts/Roboto.ttf")
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Why a render stopped before finishing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cancelled {
+    /// `cancel` was called on the token.
+    Requested,
+    /// The deadline passed.
+    TimedOut,
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cancelled::Requested => write!(f, "Render was cancelled"),
+            Cancelled::TimedOut => write!(f, "Render timed out"),
+        }
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Stops a running render, either on request from another thread or once a
+/// deadline passes. Renders call `check` between operations and periodically
+/// inside per-pixel loops.
+///
+/// Clones share the cancelled flag, so cancelling any of them stops them all.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a token sharing the cancelled flag whose deadline is at most
+    /// `timeout` from now.
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        let deadline = Instant::now() + timeout;
+        Self {
+            cancelled: self.cancelled.clone(),
+            deadline: Some(self.deadline.map_or(deadline, |d| d.min(deadline))),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether `cancel` was called. Doesn't look at the deadline.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Fails if the token was cancelled or its deadline has passed.
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            return Err(Cancelled::Requested);
+        }
+        if let Some(deadline) = self.deadline
+            && Instant::now() >= deadline
+        {
+            return Err(Cancelled::TimedOut);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn fresh_tokens_pass() {
+        let token = CancellationToken::new();
+        assert_eq!(token.check(), Ok(()));
+        assert_eq!(token.with_timeout(LONG).check(), Ok(()));
+    }
+
+    #[test]
+    fn children_share_the_cancelled_flag() {
+        let parent = CancellationToken::new();
+        let child = parent.with_timeout(LONG);
+        child.cancel();
+        assert!(parent.is_cancelled());
+        assert_eq!(parent.check(), Err(Cancelled::Requested));
+
+        let parent = CancellationToken::new();
+        let child = parent.with_timeout(LONG);
+        parent.clone().cancel();
+        assert_eq!(child.check(), Err(Cancelled::Requested));
+    }
+
+    #[test]
+    fn deadlines_take_the_earlier_of_parent_and_child() {
+        let parent = CancellationToken::new().with_timeout(Duration::ZERO);
+        // A longer child timeout can't extend the parent's deadline.
+        assert_eq!(parent.with_timeout(LONG).check(), Err(Cancelled::TimedOut));
+
+        let parent = CancellationToken::new().with_timeout(LONG);
+        let child = parent.with_timeout(Duration::ZERO);
+        assert_eq!(child.check(), Err(Cancelled::TimedOut));
+        assert_eq!(parent.check(), Ok(()));
+    }
+
+    #[test]
+    fn cancellation_is_reported_before_a_timeout() {
+        let token = CancellationToken::new().with_timeout(Duration::ZERO);
+        token.cancel();
+        assert_eq!(token.check(), Err(Cancelled::Requested));
+        // The deadline alone doesn't count as cancelled.
+        assert!(
+            !CancellationToken::new()
+                .with_timeout(Duration::ZERO)
+                .is_cancelled()
+        );
+    }
+}
@@ -4,6 +4,9 @@ use std::path::PathBuf;
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub version: String,
+    /// Seed for every random operation. Operations with their own `seed` use
+    /// that instead. A random seed is picked when unset.
+    pub seed: Option<u64>,
     pub input: InputConfig,
     pub output: OutputConfig,
     pub operations: Vec<Operation>,
@@ -34,7 +37,9 @@ pub enum Operation {
         x: i32,
         y: i32,
         opacity: Option<f32>,
-        blend_mode: Option<String>,
+        /// How the overlay's colors combine with the image underneath.
+        /// Unset or `null` means `normal`.
+        blend_mode: Option<BlendMode>,
     },
     Filter(FilterOperation),
     Text {
@@ -49,22 +54,577 @@ pub enum Operation {
     },
 }
 
+impl Operation {
+    /// Short name of the operation, e.g. `resize` or `blur` for filters.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Resize { .. } => "resize",
+            Operation::Overlay { .. } => "overlay",
+            Operation::Filter(filter) => filter.name(),
+            Operation::Text { .. } => "text",
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "name", rename_all = "snake_case")]
 pub enum FilterOperation {
-    Grain { intensity: f32 },
-    Blur { radius: f32 },
+    /// Film grain. `intensity` scales the noise, `size` is the grain size in
+    /// pixels and `response` (0 to 1) takes grain away from shadows and
+    /// highlights so midtones get the most.
+    Grain {
+        intensity: f32,
+        #[serde(default)]
+        distribution: GrainDistribution,
+        #[serde(default = "default_grain_size")]
+        size: f32,
+        /// Uses the same noise on every channel instead of colored noise.
+        #[serde(default = "default_monochrome")]
+        monochrome: bool,
+        #[serde(default)]
+        response: f32,
+        seed: Option<u64>,
+    },
+    Blur {
+        radius: f32,
+    },
     DoubleVision {
         offset_x: i32,
         offset_y: i32,
         opacity: f32,
     },
-    Vignette { intensity: f32 },
+    Vignette {
+        intensity: f32,
+    },
     Sepia,
-    Brightness { value: f32 },
-    Contrast { value: f32 },
-    Saturation { value: f32 },
-    HueRotate { degrees: f32 },
+    Brightness {
+        value: f32,
+    },
+    Contrast {
+        value: f32,
+    },
+    Saturation {
+        value: f32,
+    },
+    HueRotate {
+        degrees: f32,
+    },
+    /// Shifts the red, green and blue channels independently. Offsets are
+    /// `[x, y]` in pixels; `radial` also pushes red outward and blue inward,
+    /// from nothing at the center to that many pixels at the corners.
+    ChromaticAberration {
+        #[serde(default)]
+        red: [f32; 2],
+        #[serde(default)]
+        green: [f32; 2],
+        #[serde(default)]
+        blue: [f32; 2],
+        #[serde(default)]
+        radial: f32,
+        #[serde(default)]
+        edge: EdgeMode,
+    },
+    /// Sorts runs of pixels along rows or columns. A run is a stretch of
+    /// neighbouring pixels whose key lies within `threshold` (0 to 1).
+    PixelSort {
+        #[serde(default)]
+        key: SortKey,
+        #[serde(default)]
+        direction: Direction,
+        #[serde(default = "default_sort_threshold")]
+        threshold: [f32; 2],
+        #[serde(default)]
+        reverse: bool,
+        /// Cuts runs at random lengths of up to this many pixels.
+        max_run: Option<u32>,
+        seed: Option<u64>,
+    },
+    /// Cuts the image into `slices` horizontal slices of random height and
+    /// shifts each sideways by up to `max_offset` pixels.
+    SliceDisplacement {
+        slices: u32,
+        max_offset: u32,
+        #[serde(default = "default_slice_edge")]
+        edge: EdgeMode,
+        seed: Option<u64>,
+    },
+    /// Damages a share (`amount`, 0 to 1) of the `block_size` blocks the way
+    /// a corrupted JPEG does: misplaced, flattened, smeared or channel-swapped.
+    BlockCorruption {
+        #[serde(default = "default_block_size")]
+        block_size: u32,
+        amount: f32,
+        seed: Option<u64>,
+    },
+    /// Turns the image black and white: pixels with a luminance of at least
+    /// `level` (0 to 1) become white.
+    Threshold {
+        #[serde(default = "default_threshold_level")]
+        level: f32,
+    },
+    /// Reduces each channel to `levels` evenly spaced values.
+    Posterize {
+        levels: u32,
+    },
+    /// Maps the image to `palette` using a Bayer matrix of size 2, 4 or 8.
+    OrderedDither {
+        #[serde(default = "default_matrix_size")]
+        matrix_size: u32,
+        #[serde(default)]
+        palette: Palette,
+    },
+    /// Maps the image to `palette`, spreading each pixel's error over its
+    /// unprocessed neighbours.
+    ErrorDiffusion {
+        #[serde(default)]
+        kernel: DiffusionKernel,
+        #[serde(default)]
+        palette: Palette,
+    },
+    /// Prints the image as a screen of `pattern` cells `cell_size` pixels
+    /// wide, rotated by `angle` degrees. In CMYK mode each ink gets its own
+    /// screen, rotated by the matching entry of `cmyk_angles`.
+    Halftone {
+        #[serde(default)]
+        pattern: HalftonePattern,
+        #[serde(default = "default_cell_size")]
+        cell_size: f32,
+        #[serde(default = "default_screen_angle")]
+        angle: f32,
+        #[serde(default)]
+        mode: HalftoneMode,
+        /// Cyan, magenta, yellow and black.
+        #[serde(default = "default_cmyk_angles")]
+        cmyk_angles: [f32; 4],
+    },
+    /// Grades colors with a 3D LUT, either a `.cube` file or a Hald CLUT
+    /// image. `strength` (0 to 1) mixes the graded colors with the original.
+    Lut {
+        lut: PathBuf,
+        #[serde(default)]
+        interpolation: LutInterpolation,
+        #[serde(default = "default_strength")]
+        strength: f32,
+    },
+    /// Remaps tones through curves of `[input, output]` points (0 to 1),
+    /// joined by a monotone spline. `rgb` applies to every channel before the
+    /// per-channel curves; `luma` changes brightness without touching hue.
+    /// Empty curves leave the image alone.
+    Curves {
+        #[serde(default)]
+        rgb: Vec<[f32; 2]>,
+        #[serde(default)]
+        red: Vec<[f32; 2]>,
+        #[serde(default)]
+        green: Vec<[f32; 2]>,
+        #[serde(default)]
+        blue: Vec<[f32; 2]>,
+        #[serde(default)]
+        luma: Vec<[f32; 2]>,
+    },
+    /// Stretches `input_black..input_white` to `output_black..output_white`
+    /// (0 to 1), with `gamma` above 1 brightening the midtones. With `auto`
+    /// the input range is taken from the luminance histogram instead, ignoring
+    /// the darkest and brightest `auto_clip` share of pixels.
+    Levels {
+        #[serde(default)]
+        input_black: f32,
+        #[serde(default = "default_white")]
+        input_white: f32,
+        #[serde(default = "default_gamma")]
+        gamma: f32,
+        #[serde(default)]
+        output_black: f32,
+        #[serde(default = "default_white")]
+        output_white: f32,
+        #[serde(default)]
+        auto: bool,
+        #[serde(default = "default_auto_clip")]
+        auto_clip: f32,
+    },
+    /// Maps luminance onto a ramp through `stops`. The mapped colors are laid
+    /// over the original with `blend_mode` at `opacity`.
+    GradientMap {
+        stops: Vec<ColorStop>,
+        #[serde(default)]
+        blend_mode: BlendMode,
+        #[serde(default = "default_strength")]
+        opacity: f32,
+    },
+    /// A gradient map from `shadows` to `highlights`, through `midtones` for
+    /// a tritone.
+    Duotone {
+        shadows: String,
+        highlights: String,
+        midtones: Option<String>,
+        #[serde(default)]
+        blend_mode: BlendMode,
+        #[serde(default = "default_strength")]
+        opacity: f32,
+    },
+    /// Sharpens with a 3x3 kernel.
+    Sharpen {
+        #[serde(default = "default_strength")]
+        amount: f32,
+    },
+    /// Adds `amount` times the difference between the image and a blur of
+    /// `radius`, skipping differences below `threshold` (0 to 1) so flat areas
+    /// and noise stay smooth.
+    UnsharpMask {
+        #[serde(default = "default_unsharp_radius")]
+        radius: f32,
+        amount: f32,
+        #[serde(default)]
+        threshold: f32,
+    },
+    /// Draws edges white on black. `low` and `high` (0 to 1) are Canny's
+    /// hysteresis thresholds.
+    EdgeDetect {
+        #[serde(default)]
+        method: EdgeMethod,
+        #[serde(default = "default_canny_low")]
+        low: f32,
+        #[serde(default = "default_canny_high")]
+        high: f32,
+    },
+    /// Turns the image into a grey relief lit from the top left.
+    Emboss {
+        #[serde(default = "default_strength")]
+        amount: f32,
+    },
+    /// Convolves with `kernel`, given as rows of odd length. With `normalize`
+    /// the kernel is divided by its sum, if that isn't zero. `bias` (0 to 1) is
+    /// added to the result.
+    Convolve {
+        kernel: Vec<Vec<f32>>,
+        #[serde(default = "default_normalize")]
+        normalize: bool,
+        #[serde(default)]
+        bias: f32,
+        #[serde(default)]
+        edge: EdgeMode,
+    },
+    /// Smears the image `length` pixels along `angle` degrees.
+    MotionBlur {
+        angle: f32,
+        length: f32,
+        #[serde(default)]
+        edge: EdgeMode,
+    },
+    /// Spins the image `angle` degrees around `center`, given as a fraction
+    /// of the width and height.
+    RadialBlur {
+        angle: f32,
+        #[serde(default = "default_center")]
+        center: [f32; 2],
+    },
+    /// Streaks the image towards `center`, each pixel covering `amount` (0 to
+    /// 1) of its distance to it.
+    ZoomBlur {
+        amount: f32,
+        #[serde(default = "default_center")]
+        center: [f32; 2],
+    },
+    /// Keeps a horizontal band around `focus` (0 to 1, top to bottom) of
+    /// height `width` sharp, blurring more and more over `transition` beyond
+    /// it, up to `radius`.
+    TiltShift {
+        #[serde(default = "default_focus")]
+        focus: f32,
+        #[serde(default = "default_focus_width")]
+        width: f32,
+        #[serde(default = "default_focus_transition")]
+        transition: f32,
+        #[serde(default = "default_tilt_shift_radius")]
+        radius: f32,
+    },
+    /// Makes highlights glow. Pixels brighter than `threshold` (0 to 1) are
+    /// blurred at each of `radii`, multiplied by `tint` if set and laid over
+    /// the image with `blend_mode`, covering it as much as the glow is bright
+    /// times `intensity`.
+    Bloom {
+        #[serde(default = "default_bloom_threshold")]
+        threshold: f32,
+        #[serde(default = "default_bloom_radii")]
+        radii: Vec<f32>,
+        tint: Option<String>,
+        #[serde(default = "default_bloom_blend_mode")]
+        blend_mode: BlendMode,
+        #[serde(default = "default_strength")]
+        intensity: f32,
+    },
+}
+
+fn default_grain_size() -> f32 {
+    1.0
+}
+
+fn default_monochrome() -> bool {
+    true
+}
+
+fn default_threshold_level() -> f32 {
+    0.5
+}
+
+fn default_matrix_size() -> u32 {
+    4
+}
+
+fn default_cell_size() -> f32 {
+    8.0
+}
+
+fn default_screen_angle() -> f32 {
+    45.0
+}
+
+fn default_cmyk_angles() -> [f32; 4] {
+    [15.0, 75.0, 0.0, 45.0]
+}
+
+fn default_strength() -> f32 {
+    1.0
+}
+
+fn default_white() -> f32 {
+    1.0
+}
+
+fn default_gamma() -> f32 {
+    1.0
+}
+
+fn default_auto_clip() -> f32 {
+    0.005
+}
+
+fn default_unsharp_radius() -> f32 {
+    1.0
+}
+
+fn default_canny_low() -> f32 {
+    0.05
+}
+
+fn default_canny_high() -> f32 {
+    0.15
+}
+
+fn default_normalize() -> bool {
+    true
+}
+
+fn default_center() -> [f32; 2] {
+    [0.5, 0.5]
+}
+
+fn default_focus() -> f32 {
+    0.5
+}
+
+fn default_focus_width() -> f32 {
+    0.2
+}
+
+fn default_focus_transition() -> f32 {
+    0.25
+}
+
+fn default_tilt_shift_radius() -> f32 {
+    8.0
+}
+
+fn default_bloom_threshold() -> f32 {
+    0.7
+}
+
+fn default_bloom_radii() -> Vec<f32> {
+    vec![4.0, 12.0, 32.0]
+}
+
+fn default_bloom_blend_mode() -> BlendMode {
+    BlendMode::Screen
+}
+
+fn default_sort_threshold() -> [f32; 2] {
+    [0.25, 0.8]
+}
+
+fn default_slice_edge() -> EdgeMode {
+    EdgeMode::Wrap
+}
+
+fn default_block_size() -> u32 {
+    8
+}
+
+/// How grain noise is distributed. Both have the same standard deviation for
+/// a given intensity; gaussian keeps most values small with occasional strong
+/// ones, which looks more like film.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GrainDistribution {
+    #[default]
+    Uniform,
+    Gaussian,
+}
+
+/// Colors to dither to: either hex colors such as `["#000000", "#ffffff"]`
+/// or a number of colors to pick from the image. Defaults to black and white.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Palette {
+    Colors(Vec<String>),
+    Quantized(u32),
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::Colors(vec!["#000000".to_string(), "#ffffff".to_string()])
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffusionKernel {
+    #[default]
+    FloydSteinberg,
+    Atkinson,
+    Jarvis,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HalftonePattern {
+    #[default]
+    Dot,
+    Line,
+    Cross,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HalftoneMode {
+    /// Black ink on white paper.
+    #[default]
+    Mono,
+    /// Cyan, magenta, yellow and black inks.
+    Cmyk,
+}
+
+/// How colors between LUT entries are computed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LutInterpolation {
+    Trilinear,
+    #[default]
+    Tetrahedral,
+}
+
+/// How colors laid over an image, by an overlay or a filter, are combined
+/// with it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    SoftLight,
+    /// Adds the colors, clipping at white.
+    Add,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EdgeMethod {
+    /// Gradient magnitude.
+    #[default]
+    Sobel,
+    /// Thin, connected edges.
+    Canny,
+}
+
+/// What pixel sorting compares.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    #[default]
+    Luminance,
+    Hue,
+    Saturation,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    #[default]
+    Horizontal,
+    Vertical,
+}
+
+/// What filters sample outside the image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EdgeMode {
+    /// Repeat the nearest edge pixel.
+    #[default]
+    Clamp,
+    /// Continue from the opposite edge.
+    Wrap,
+    /// Reflect back into the image.
+    Mirror,
+    /// Use black.
+    Black,
+}
+
+impl FilterOperation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterOperation::Grain { .. } => "grain",
+            FilterOperation::Blur { .. } => "blur",
+            FilterOperation::DoubleVision { .. } => "double_vision",
+            FilterOperation::Vignette { .. } => "vignette",
+            FilterOperation::Sepia => "sepia",
+            FilterOperation::Brightness { .. } => "brightness",
+            FilterOperation::Contrast { .. } => "contrast",
+            FilterOperation::Saturation { .. } => "saturation",
+            FilterOperation::HueRotate { .. } => "hue_rotate",
+            FilterOperation::ChromaticAberration { .. } => "chromatic_aberration",
+            FilterOperation::PixelSort { .. } => "pixel_sort",
+            FilterOperation::SliceDisplacement { .. } => "slice_displacement",
+            FilterOperation::BlockCorruption { .. } => "block_corruption",
+            FilterOperation::Threshold { .. } => "threshold",
+            FilterOperation::Posterize { .. } => "posterize",
+            FilterOperation::OrderedDither { .. } => "ordered_dither",
+            FilterOperation::ErrorDiffusion { .. } => "error_diffusion",
+            FilterOperation::Halftone { .. } => "halftone",
+            FilterOperation::Lut { .. } => "lut",
+            FilterOperation::Curves { .. } => "curves",
+            FilterOperation::Levels { .. } => "levels",
+            FilterOperation::GradientMap { .. } => "gradient_map",
+            FilterOperation::Duotone { .. } => "duotone",
+            FilterOperation::Sharpen { .. } => "sharpen",
+            FilterOperation::UnsharpMask { .. } => "unsharp_mask",
+            FilterOperation::EdgeDetect { .. } => "edge_detect",
+            FilterOperation::Emboss { .. } => "emboss",
+            FilterOperation::Convolve { .. } => "convolve",
+            FilterOperation::MotionBlur { .. } => "motion_blur",
+            FilterOperation::RadialBlur { .. } => "radial_blur",
+            FilterOperation::ZoomBlur { .. } => "zoom_blur",
+            FilterOperation::TiltShift { .. } => "tilt_shift",
+            FilterOperation::Bloom { .. } => "bloom",
+        }
+    }
+}
+
+/// A hex color at `position` (0 to 1) along a gradient.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ColorStop {
+    pub color: String,
+    pub position: f32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -80,3 +640,41 @@ pub struct Shadow {
     pub offset_x: i32,
     pub offset_y: i32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlay_blend_mode(blend_mode: Option<serde_json::Value>) -> BlendMode {
+        let mut overlay =
+            serde_json::json!({"type": "overlay", "image": "logo.png", "x": 0, "y": 0});
+        if let Some(blend_mode) = blend_mode {
+            overlay["blend_mode"] = blend_mode;
+        }
+        match serde_json::from_value(overlay).unwrap() {
+            Operation::Overlay { blend_mode, .. } => blend_mode.unwrap_or_default(),
+            operation => panic!("parsed as {}", operation.name()),
+        }
+    }
+
+    #[test]
+    fn overlay_blend_mode_defaults_to_normal() {
+        assert_eq!(overlay_blend_mode(None), BlendMode::Normal);
+        assert_eq!(
+            overlay_blend_mode(Some(serde_json::Value::Null)),
+            BlendMode::Normal
+        );
+
+        let modes = [
+            ("normal", BlendMode::Normal),
+            ("multiply", BlendMode::Multiply),
+            ("screen", BlendMode::Screen),
+            ("overlay", BlendMode::Overlay),
+            ("soft_light", BlendMode::SoftLight),
+            ("add", BlendMode::Add),
+        ];
+        for (name, mode) in modes {
+            assert_eq!(overlay_blend_mode(Some(name.into())), mode);
+        }
+    }
+}
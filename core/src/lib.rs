@@ -1,2 +1,6 @@
+pub mod assets;
+pub mod cancel;
 pub mod config;
+pub mod lut;
 pub mod processor;
+pub mod storage;
@@ -0,0 +1,397 @@
+use anyhow::{Result, anyhow, bail};
+use image::GenericImageView;
+
+use crate::config::LutInterpolation;
+
+/// Largest LUT accepted, in entries per axis. Real grading LUTs are 17 to 65.
+const MAX_SIZE: usize = 256;
+
+/// A 3D color lookup table with `size` entries per axis.
+#[derive(Debug, Clone)]
+pub struct Lut {
+    size: usize,
+    /// Output colors (0 to 1), red changing fastest, then green, then blue.
+    table: Vec<[f32; 3]>,
+    domain_min: [f32; 3],
+    domain_max: [f32; 3],
+}
+
+impl Lut {
+    /// Loads a Hald CLUT if `data` is an image, otherwise parses it as a
+    /// `.cube` file.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if image::guess_format(data).is_ok() {
+            Self::from_hald(&image::load_from_memory(data)?)
+        } else {
+            Self::from_cube(std::str::from_utf8(data)?)
+        }
+    }
+
+    /// Parses an Adobe/Resolve `.cube` file. Only 3D tables are supported.
+    pub fn from_cube(text: &str) -> Result<Self> {
+        let mut size = None;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut table = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let keyword = words.next().unwrap_or_default();
+            match keyword {
+                "TITLE" => {}
+                "LUT_3D_SIZE" => {
+                    let value: usize = words
+                        .next()
+                        .and_then(|value| value.parse().ok())
+                        .ok_or_else(|| anyhow!("Invalid LUT_3D_SIZE on line {}", number + 1))?;
+                    if !(2..=MAX_SIZE).contains(&value) {
+                        bail!("LUT_3D_SIZE must be between 2 and {}", MAX_SIZE);
+                    }
+                    size = Some(value);
+                }
+                "LUT_1D_SIZE" => bail!("1D LUTs are not supported"),
+                "DOMAIN_MIN" => domain_min = Self::triple(&Self::numbers(words, number)?, number)?,
+                "DOMAIN_MAX" => domain_max = Self::triple(&Self::numbers(words, number)?, number)?,
+                // Resolve's variant of the domain, one range for all channels.
+                "LUT_3D_INPUT_RANGE" => match Self::numbers(words, number)?[..] {
+                    [min, max] => {
+                        domain_min = [min; 3];
+                        domain_max = [max; 3];
+                    }
+                    _ => bail!("Invalid LUT_3D_INPUT_RANGE on line {}", number + 1),
+                },
+                _ if keyword.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                    bail!("Unknown keyword {} on line {}", keyword, number + 1)
+                }
+                _ => table.push(Self::triple(
+                    &Self::numbers(line.split_whitespace(), number)?,
+                    number,
+                )?),
+            }
+        }
+
+        let size = size.ok_or_else(|| anyhow!("Missing LUT_3D_SIZE"))?;
+        if table.len() != size.pow(3) {
+            bail!(
+                "Expected {} table entries for size {}, found {}",
+                size.pow(3),
+                size,
+                table.len()
+            );
+        }
+        if (0..3).any(|channel| domain_max[channel] <= domain_min[channel]) {
+            bail!("DOMAIN_MAX must be greater than DOMAIN_MIN");
+        }
+
+        Ok(Self {
+            size,
+            table,
+            domain_min,
+            domain_max,
+        })
+    }
+
+    /// Reads a Hald CLUT: a square image `level`³ pixels wide holding a
+    /// table with `level`² entries per axis, red changing fastest.
+    pub fn from_hald(img: &image::DynamicImage) -> Result<Self> {
+        let (width, height) = img.dimensions();
+        let level = (width as f32).cbrt().round() as usize;
+        if width != height || level.pow(3) != width as usize || level < 2 {
+            bail!("Hald CLUT must be a square image whose width is a cube, e.g. 512x512");
+        }
+        let size = level * level;
+        if size > MAX_SIZE {
+            bail!("Hald CLUT level must be at most 16");
+        }
+
+        let table = img
+            .to_rgb32f()
+            .pixels()
+            .map(|pixel| [pixel[0], pixel[1], pixel[2]])
+            .collect();
+
+        Ok(Self {
+            size,
+            table,
+            domain_min: [0.0; 3],
+            domain_max: [1.0; 3],
+        })
+    }
+
+    /// Entries per axis.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Looks up an RGB color (0 to 1).
+    pub fn apply(&self, rgb: [f32; 3], interpolation: LutInterpolation) -> [f32; 3] {
+        let max = (self.size - 1) as f32;
+        let position: [f32; 3] = std::array::from_fn(|channel| {
+            let range = self.domain_max[channel] - self.domain_min[channel];
+            ((rgb[channel] - self.domain_min[channel]) / range * max).clamp(0.0, max)
+        });
+        let base = position.map(|value| (value.floor() as usize).min(self.size - 2));
+        let [fr, fg, fb]: [f32; 3] =
+            std::array::from_fn(|channel| position[channel] - base[channel] as f32);
+
+        let corner = |r: usize, g: usize, b: usize| {
+            self.table
+                [(base[2] + b) * self.size * self.size + (base[1] + g) * self.size + base[0] + r]
+        };
+        let mix = |weights: [(f32, [f32; 3]); 4]| -> [f32; 3] {
+            std::array::from_fn(|channel| {
+                weights
+                    .iter()
+                    .map(|(weight, color)| weight * color[channel])
+                    .sum()
+            })
+        };
+
+        match interpolation {
+            LutInterpolation::Trilinear => {
+                let lerp = |a: [f32; 3], b: [f32; 3], t: f32| -> [f32; 3] {
+                    std::array::from_fn(|channel| a[channel] + (b[channel] - a[channel]) * t)
+                };
+                let c00 = lerp(corner(0, 0, 0), corner(1, 0, 0), fr);
+                let c10 = lerp(corner(0, 1, 0), corner(1, 1, 0), fr);
+                let c01 = lerp(corner(0, 0, 1), corner(1, 0, 1), fr);
+                let c11 = lerp(corner(0, 1, 1), corner(1, 1, 1), fr);
+                lerp(lerp(c00, c10, fg), lerp(c01, c11, fg), fb)
+            }
+            // Splits the cell into six tetrahedra along its main diagonal and
+            // interpolates within the one containing the color. Keeps grey
+            // ramps neutral, unlike trilinear.
+            LutInterpolation::Tetrahedral => {
+                let (c000, c111) = (corner(0, 0, 0), corner(1, 1, 1));
+                if fr > fg {
+                    if fg > fb {
+                        mix([
+                            (1.0 - fr, c000),
+                            (fr - fg, corner(1, 0, 0)),
+                            (fg - fb, corner(1, 1, 0)),
+                            (fb, c111),
+                        ])
+                    } else if fr > fb {
+                        mix([
+                            (1.0 - fr, c000),
+                            (fr - fb, corner(1, 0, 0)),
+                            (fb - fg, corner(1, 0, 1)),
+                            (fg, c111),
+                        ])
+                    } else {
+                        mix([
+                            (1.0 - fb, c000),
+                            (fb - fr, corner(0, 0, 1)),
+                            (fr - fg, corner(1, 0, 1)),
+                            (fg, c111),
+                        ])
+                    }
+                } else if fb > fg {
+                    mix([
+                        (1.0 - fb, c000),
+                        (fb - fg, corner(0, 0, 1)),
+                        (fg - fr, corner(0, 1, 1)),
+                        (fr, c111),
+                    ])
+                } else if fb > fr {
+                    mix([
+                        (1.0 - fg, c000),
+                        (fg - fb, corner(0, 1, 0)),
+                        (fb - fr, corner(0, 1, 1)),
+                        (fr, c111),
+                    ])
+                } else {
+                    mix([
+                        (1.0 - fg, c000),
+                        (fg - fr, corner(0, 1, 0)),
+                        (fr - fb, corner(1, 1, 0)),
+                        (fb, c111),
+                    ])
+                }
+            }
+        }
+    }
+
+    fn numbers<'a>(words: impl Iterator<Item = &'a str>, number: usize) -> Result<Vec<f32>> {
+        words
+            .map(|word| {
+                word.parse()
+                    .map_err(|_| anyhow!("Invalid number on line {}", number + 1))
+            })
+            .collect()
+    }
+
+    fn triple(values: &[f32], number: usize) -> Result<[f32; 3]> {
+        match values {
+            &[r, g, b] => Ok([r, g, b]),
+            _ => bail!("Expected three numbers on line {}", number + 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, RgbImage};
+
+    /// A `.cube` file of the given size mapping every color through `f`.
+    fn cube(size: usize, header: &str, f: impl Fn([f32; 3]) -> [f32; 3]) -> String {
+        let max = (size - 1) as f32;
+        let mut text = format!(
+            "TITLE \"test\"\n# comment\nLUT_3D_SIZE {}\n{}\n",
+            size, header
+        );
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    let [r, g, b] = f([r as f32 / max, g as f32 / max, b as f32 / max]);
+                    text += &format!("{} {} {}\n", r, g, b);
+                }
+            }
+        }
+        text
+    }
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3]) {
+        for channel in 0..3 {
+            assert!(
+                (actual[channel] - expected[channel]).abs() < 1e-5,
+                "{:?} != {:?}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    const BOTH: [LutInterpolation; 2] =
+        [LutInterpolation::Trilinear, LutInterpolation::Tetrahedral];
+
+    #[test]
+    fn parses_cube_files() {
+        let lut = Lut::from_cube(&cube(3, "", |rgb| rgb)).unwrap();
+        assert_eq!(lut.size(), 3);
+        for interpolation in BOTH {
+            assert_close(lut.apply([0.2, 0.5, 0.9], interpolation), [0.2, 0.5, 0.9]);
+            // Out of range colors are clamped to the table.
+            assert_close(lut.apply([-1.0, 0.5, 2.0], interpolation), [0.0, 0.5, 1.0]);
+        }
+    }
+
+    #[test]
+    fn scales_colors_to_the_domain() {
+        let lut =
+            Lut::from_cube(&cube(2, "DOMAIN_MIN 0 0 0\nDOMAIN_MAX 2 2 2", |rgb| rgb)).unwrap();
+        assert_close(
+            lut.apply([1.0, 0.5, 2.0], LutInterpolation::Trilinear),
+            [0.5, 0.25, 1.0],
+        );
+
+        let lut = Lut::from_cube(&cube(2, "LUT_3D_INPUT_RANGE 0 4", |rgb| rgb)).unwrap();
+        assert_close(
+            lut.apply([1.0, 2.0, 4.0], LutInterpolation::Trilinear),
+            [0.25, 0.5, 1.0],
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_cube_files() {
+        let error = |text: &str| Lut::from_cube(text).unwrap_err().to_string();
+        assert_eq!(error("0 0 0\n"), "Missing LUT_3D_SIZE");
+        assert_eq!(error("LUT_1D_SIZE 16\n"), "1D LUTs are not supported");
+        assert_eq!(
+            error("LUT_3D_SIZE 1\n"),
+            "LUT_3D_SIZE must be between 2 and 256"
+        );
+        assert_eq!(
+            error("LUT_3D_SIZE 2\n0 0 0\n"),
+            "Expected 8 table entries for size 2, found 1"
+        );
+        assert_eq!(
+            error("LUT_3D_SIZE 2\nLUT_WHATEVER 1\n"),
+            "Unknown keyword LUT_WHATEVER on line 2"
+        );
+        assert_eq!(
+            error("LUT_3D_SIZE 2\n0 0\n"),
+            "Expected three numbers on line 2"
+        );
+        assert_eq!(
+            error(&cube(2, "DOMAIN_MIN 1 1 1\nDOMAIN_MAX 0 1 1", |rgb| rgb)),
+            "DOMAIN_MAX must be greater than DOMAIN_MIN"
+        );
+    }
+
+    /// An identity Hald CLUT of the given level.
+    fn hald(level: u32) -> DynamicImage {
+        let size = level * level;
+        let max = (size - 1) as f32;
+        DynamicImage::ImageRgb8(RgbImage::from_fn(size * level, size * level, |x, y| {
+            let index = y * size * level + x;
+            let channel = |value: u32| (value as f32 / max * 255.0).round() as u8;
+            image::Rgb([
+                channel(index % size),
+                channel(index / size % size),
+                channel(index / size / size),
+            ])
+        }))
+    }
+
+    #[test]
+    fn reads_hald_images() {
+        let mut png = Vec::new();
+        hald(2)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let lut = Lut::parse(&png).unwrap();
+        assert_eq!(lut.size(), 4);
+        assert_close(
+            lut.apply([1.0 / 3.0, 2.0 / 3.0, 1.0], LutInterpolation::Tetrahedral),
+            [1.0 / 3.0, 2.0 / 3.0, 1.0],
+        );
+
+        let not_a_cube = DynamicImage::ImageRgb8(RgbImage::new(10, 10));
+        assert!(Lut::from_hald(&not_a_cube).is_err());
+    }
+
+    #[test]
+    fn interpolates_affine_tables_exactly() {
+        // Any affine table is reproduced exactly by both methods, whichever of
+        // the six tetrahedra the color falls into.
+        let lut = Lut::from_cube(&cube(2, "", |[r, g, b]| [g, b, 0.5 * r + 0.25])).unwrap();
+        for [r, g, b] in [
+            [0.8, 0.5, 0.2],
+            [0.8, 0.2, 0.5],
+            [0.5, 0.2, 0.8],
+            [0.2, 0.5, 0.8],
+            [0.2, 0.8, 0.5],
+            [0.5, 0.8, 0.2],
+        ] {
+            for interpolation in BOTH {
+                assert_close(lut.apply([r, g, b], interpolation), [g, b, 0.5 * r + 0.25]);
+            }
+        }
+    }
+
+    #[test]
+    fn tetrahedral_keeps_greys_on_the_diagonal() {
+        // Only the black and white corners are neutral; a grey input must only
+        // mix those two, while trilinear also picks up the colored corners.
+        let lut = Lut::from_cube(&cube(2, "", |[r, g, b]| {
+            if r == g && g == b {
+                [r, g, b]
+            } else {
+                [1.0, 0.0, 0.0]
+            }
+        }))
+        .unwrap();
+        assert_close(
+            lut.apply([0.25, 0.25, 0.25], LutInterpolation::Tetrahedral),
+            [0.25, 0.25, 0.25],
+        );
+        let trilinear = lut.apply([0.25, 0.25, 0.25], LutInterpolation::Trilinear);
+        assert!(trilinear[0] > trilinear[1]);
+    }
+}
@@ -1,51 +1,158 @@
-use crate::config::{Config, FilterOperation, Operation, Shadow, Stroke};
+use crate::assets::{AssetKind, AssetResolver, LocalAssets};
+use crate::cancel::CancellationToken;
+use crate::config::{
+    BlendMode, Config, DiffusionKernel, Direction, EdgeMethod, EdgeMode, FilterOperation,
+    GrainDistribution, HalftoneMode, HalftonePattern, LutInterpolation, Operation, Palette, Shadow,
+    SortKey, Stroke,
+};
+use crate::lut::Lut;
+use crate::storage::{DefaultStorage, Storage};
 use anyhow::Result;
-use image::{DynamicImage, ImageFormat, Rgba, imageops::colorops};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageFormat, Rgba, RgbaImage, imageops::colorops};
 use imageproc::drawing;
-use std::path::{Path, PathBuf};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Most samples a path blur takes per pixel. Longer paths are sampled sparser.
+const MAX_BLUR_SAMPLES: usize = 64;
+
+/// Sample positions are clamped to this many pixels either way, so huge
+/// offsets from a config can't overflow the integer coordinate maths.
+const MAX_SAMPLE_COORDINATE: f32 = 1e9;
+
+/// Everything a render needs besides the config: where asset references
+/// point to, where files are read from and written to, and when to stop.
+#[derive(Clone)]
+pub struct RenderContext {
+    pub assets: Arc<dyn AssetResolver>,
+    pub storage: Arc<dyn Storage>,
+    /// Checked between operations and inside per-pixel loops.
+    pub cancel: CancellationToken,
+    /// Longest time a single operation may take.
+    pub operation_timeout: Option<Duration>,
+}
+
+impl Default for RenderContext {
+    /// Local assets, local files and S3 if configured in the environment.
+    fn default() -> Self {
+        Self {
+            assets: Arc::new(LocalAssets),
+            storage: Arc::new(DefaultStorage::from_env()),
+            cancel: CancellationToken::new(),
+            operation_timeout: None,
+        }
+    }
+}
 
 pub struct ImageProcessor;
 
 impl ImageProcessor {
     pub fn process(config: &Config) -> Result<DynamicImage> {
-        let mut img = image::open(&config.input.source)?;
+        Self::process_with(config, &RenderContext::default())
+    }
+
+    /// Like `process`, but with explicit asset lookup and storage.
+    ///
+    /// Set `config.seed` beforehand (see `new_seed`) to know which seed was used.
+    pub fn process_with(config: &Config, context: &RenderContext) -> Result<DynamicImage> {
+        let img = Self::load_image(&config.input.source, context)?;
+        let seed = config.seed.unwrap_or_else(Self::new_seed);
+
+        tracing::info!(
+            width = img.width(),
+            height = img.height(),
+            seed,
+            "Loaded image"
+        );
+
+        Self::apply_operations(img, &config.operations, seed, context)
+    }
 
-        println!("Loaded image: {}x{}", img.width(), img.height());
+    /// A random seed for configs without one. It stays below 2^32 so it
+    /// survives JSON clients and databases unchanged.
+    pub fn new_seed() -> u64 {
+        rand::thread_rng().r#gen::<u32>() as u64
+    }
 
-        for operation in &config.operations {
-            println!("Apply {:?}", operation);
-            img = Self::apply_operation(&img, operation)?;
+    /// Applies operations to an already loaded image. Each operation runs in
+    /// its own `operation` span and logs how long it took. Random operations
+    /// without their own seed get one derived from `seed` and their position.
+    ///
+    /// Stops with a [`Cancelled`](crate::cancel::Cancelled) error once the
+    /// context's token is cancelled or a timeout passes.
+    pub fn apply_operations(
+        mut img: DynamicImage,
+        operations: &[Operation],
+        seed: u64,
+        context: &RenderContext,
+    ) -> Result<DynamicImage> {
+        for (index, operation) in operations.iter().enumerate() {
+            context.cancel.check()?;
+            let _span = tracing::info_span!("operation", name = operation.name()).entered();
+            let start = Instant::now();
+            let mut operation_context = context.clone();
+            if let Some(timeout) = context.operation_timeout {
+                operation_context.cancel = context.cancel.with_timeout(timeout);
+            }
+            let operation_seed = seed ^ (index as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            img = Self::apply_operation(&img, operation, operation_seed, &operation_context)?;
+            tracing::info!(
+                elapsed_ms = start.elapsed().as_secs_f64() * 1000.0,
+                ?operation,
+                "Applied operation"
+            );
         }
 
         Ok(img)
     }
 
-    // TODO: create missing dirs in path
     pub fn save_image(
         img: &DynamicImage,
         output_config: &crate::config::OutputConfig,
     ) -> Result<()> {
+        Self::save_image_with(img, output_config, &DefaultStorage::from_env())
+    }
+
+    /// Encodes the image and writes it to the destination through `storage`.
+    pub fn save_image_with(
+        img: &DynamicImage,
+        output_config: &crate::config::OutputConfig,
+        storage: &dyn Storage,
+    ) -> Result<()> {
+        let bytes = Self::encode_image(img, output_config)?;
+        storage.write(&output_config.destination, &bytes)
+    }
+
+    /// Encodes the image in memory in the output format.
+    pub fn encode_image(
+        img: &DynamicImage,
+        output_config: &crate::config::OutputConfig,
+    ) -> Result<Vec<u8>> {
         let format = Self::determine_format(output_config);
+        let mut bytes = Cursor::new(Vec::new());
 
-        match format {
-            ImageFormat::Jpeg => {
-                if let Some(quality) = output_config.quality {
-                    // Для простоты используем стандартное сохранение
-                    // В реальном приложении можно использовать библиотеку с поддержкой качества
-                    img.save_with_format(&output_config.destination, ImageFormat::Jpeg)?;
-                } else {
-                    img.save(&output_config.destination)?;
-                }
-            }
-            _ => {
-                img.save(&output_config.destination)?;
+        match (format, output_config.quality) {
+            (ImageFormat::Jpeg, Some(quality)) => {
+                img.write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, quality))?;
             }
+            _ => img.write_to(&mut bytes, format)?,
         }
 
-        Ok(())
+        Ok(bytes.into_inner())
+    }
+
+    /// Reads and decodes an image, detecting the format from its contents.
+    fn load_image(location: &Path, context: &RenderContext) -> Result<DynamicImage> {
+        let data = context.storage.read(location)?;
+        Ok(image::load_from_memory(&data)?)
     }
 
-    fn determine_format(output_config: &crate::config::OutputConfig) -> ImageFormat {
+    pub fn determine_format(output_config: &crate::config::OutputConfig) -> ImageFormat {
         if let Some(ref format) = output_config.format {
             match format.to_lowercase().as_str() {
                 "jpeg" | "jpg" => ImageFormat::Jpeg,
@@ -62,7 +169,12 @@ impl ImageProcessor {
         }
     }
 
-    fn apply_operation(img: &DynamicImage, operation: &Operation) -> Result<DynamicImage> {
+    fn apply_operation(
+        img: &DynamicImage,
+        operation: &Operation,
+        seed: u64,
+        context: &RenderContext,
+    ) -> Result<DynamicImage> {
         match operation {
             // TODO: allow resize without saving the aspect ratio, maybe need add as a new resize option
             Operation::Resize {
@@ -86,20 +198,34 @@ impl ImageProcessor {
                 x,
                 y,
                 opacity,
-                blend_mode: _,
+                blend_mode,
             } => {
-                let overlay = image::open(overlay_path)?;
+                let overlay = Self::load_image(
+                    &context.assets.resolve(AssetKind::Overlay, overlay_path)?,
+                    context,
+                )?;
                 let mut result = img.clone();
-
-                if let Some(opacity_value) = opacity {
-                    Self::overlay_with_opacity(&mut result, &overlay, *x, *y, *opacity_value);
+                let blend_mode = blend_mode.unwrap_or_default();
+
+                if opacity.is_some() || blend_mode != BlendMode::Normal {
+                    Self::overlay_blended(
+                        &mut result,
+                        &overlay,
+                        *x,
+                        *y,
+                        opacity.unwrap_or(1.0),
+                        blend_mode,
+                        &context.cancel,
+                    )?;
                 } else {
                     image::imageops::overlay(&mut result, &overlay, *x as i64, *y as i64);
                 }
                 Ok(result)
             }
 
-            Operation::Filter(filter_op) => Self::apply_filter_operation(img, filter_op),
+            Operation::Filter(filter_op) => {
+                Self::apply_filter_operation(img, filter_op, seed, context)
+            }
 
             Operation::Text {
                 content,
@@ -113,45 +239,316 @@ impl ImageProcessor {
             } => Self::draw_text(
                 img,
                 content,
-                font,
+                &context
+                    .storage
+                    .read(&context.assets.resolve(AssetKind::Font, font)?)?,
                 *size,
                 color,
                 *x,
                 *y,
                 stroke.as_ref(),
                 shadow.as_ref(),
+                &context.cancel,
             ),
         }
     }
 
+    /// Filters implemented by the `image` and `imageproc` crates (blur,
+    /// brightness, contrast, hue rotation, edge detection, the blur step of
+    /// unsharp masking) can't be interrupted and only see the token between
+    /// operations.
+    ///
+    /// Random filters use their own seed if set, otherwise `operation_seed`.
     fn apply_filter_operation(
         img: &DynamicImage,
         filter_op: &FilterOperation,
+        operation_seed: u64,
+        context: &RenderContext,
     ) -> Result<DynamicImage> {
+        let cancel = &context.cancel;
+        // A named generator rather than `StdRng`, whose algorithm may change
+        // between rand releases and with it every seeded render.
+        let rng = |seed: &Option<u64>| ChaCha12Rng::seed_from_u64(seed.unwrap_or(operation_seed));
         match filter_op {
-            FilterOperation::Grain { intensity } => Ok(Self::add_grain(img, *intensity)),
+            FilterOperation::Grain {
+                intensity,
+                distribution,
+                size,
+                monochrome,
+                response,
+                seed,
+            } => Self::add_grain(
+                img,
+                *intensity,
+                *distribution,
+                *size,
+                *monochrome,
+                *response,
+                &mut rng(seed),
+                cancel,
+            ),
             FilterOperation::Blur { radius } => Ok(img.blur(*radius)),
             FilterOperation::DoubleVision {
                 offset_x,
                 offset_y,
                 opacity,
-            } => Ok(Self::double_vision(img, *offset_x, *offset_y, *opacity)),
-            FilterOperation::Vignette { intensity } => Ok(Self::vignette(img, *intensity)),
-            FilterOperation::Sepia => Ok(Self::sepia(img)),
+            } => Self::double_vision(img, *offset_x, *offset_y, *opacity, cancel),
+            FilterOperation::Vignette { intensity } => Self::vignette(img, *intensity, cancel),
+            FilterOperation::Sepia => Self::sepia(img, cancel),
             FilterOperation::Brightness { value } => Ok(Self::brightness(img, *value)),
             FilterOperation::Contrast { value } => Ok(Self::contrast(img, *value)),
-            FilterOperation::Saturation { value } => Ok(Self::saturation(img, *value)),
+            FilterOperation::Saturation { value } => Self::saturation(img, *value, cancel),
             FilterOperation::HueRotate { degrees } => Ok(Self::hue_rotate(img, *degrees)),
+            FilterOperation::ChromaticAberration {
+                red,
+                green,
+                blue,
+                radial,
+                edge,
+            } => Self::chromatic_aberration(img, [*red, *green, *blue], *radial, *edge, cancel),
+            FilterOperation::PixelSort {
+                key,
+                direction,
+                threshold,
+                reverse,
+                max_run,
+                seed,
+            } => Self::pixel_sort(
+                img,
+                *key,
+                *direction,
+                *threshold,
+                *reverse,
+                *max_run,
+                &mut rng(seed),
+                cancel,
+            ),
+            FilterOperation::SliceDisplacement {
+                slices,
+                max_offset,
+                edge,
+                seed,
+            } => Self::slice_displacement(img, *slices, *max_offset, *edge, &mut rng(seed), cancel),
+            FilterOperation::BlockCorruption {
+                block_size,
+                amount,
+                seed,
+            } => Self::block_corruption(img, *block_size, *amount, &mut rng(seed), cancel),
+            FilterOperation::Threshold { level } => Self::threshold(img, *level, cancel),
+            FilterOperation::Posterize { levels } => Self::posterize(img, *levels, cancel),
+            FilterOperation::OrderedDither {
+                matrix_size,
+                palette,
+            } => Self::ordered_dither(img, *matrix_size, palette, cancel),
+            FilterOperation::ErrorDiffusion { kernel, palette } => {
+                Self::error_diffusion(img, *kernel, palette, cancel)
+            }
+            FilterOperation::Halftone {
+                pattern,
+                cell_size,
+                angle,
+                mode,
+                cmyk_angles,
+            } => Self::halftone(
+                img,
+                *pattern,
+                *cell_size,
+                *angle,
+                *mode,
+                cmyk_angles,
+                cancel,
+            ),
+            FilterOperation::Lut {
+                lut,
+                interpolation,
+                strength,
+            } => {
+                let lut = Lut::parse(
+                    &context
+                        .storage
+                        .read(&context.assets.resolve(AssetKind::Lut, lut)?)?,
+                )?;
+                Self::apply_lut(img, &lut, *interpolation, *strength, cancel)
+            }
+            FilterOperation::Curves {
+                rgb,
+                red,
+                green,
+                blue,
+                luma,
+            } => Self::curves(img, rgb, [red, green, blue], luma, cancel),
+            FilterOperation::Levels {
+                input_black,
+                input_white,
+                gamma,
+                output_black,
+                output_white,
+                auto,
+                auto_clip,
+            } => {
+                let (input_black, input_white) = if *auto {
+                    Self::auto_levels_range(img, *auto_clip)
+                } else {
+                    (*input_black, *input_white)
+                };
+                Self::levels(
+                    img,
+                    [input_black, input_white],
+                    *gamma,
+                    [*output_black, *output_white],
+                    cancel,
+                )
+            }
+            FilterOperation::GradientMap {
+                stops,
+                blend_mode,
+                opacity,
+            } => {
+                let stops = stops
+                    .iter()
+                    .map(|stop| Ok((stop.position, Self::parse_color(&stop.color)?)))
+                    .collect::<Result<Vec<_>>>()?;
+                Self::gradient_map(img, stops, *blend_mode, *opacity, cancel)
+            }
+            FilterOperation::Duotone {
+                shadows,
+                highlights,
+                midtones,
+                blend_mode,
+                opacity,
+            } => {
+                let mut stops = vec![(0.0, Self::parse_color(shadows)?)];
+                if let Some(midtones) = midtones {
+                    stops.push((0.5, Self::parse_color(midtones)?));
+                }
+                stops.push((1.0, Self::parse_color(highlights)?));
+                Self::gradient_map(img, stops, *blend_mode, *opacity, cancel)
+            }
+            FilterOperation::Sharpen { amount } => {
+                let a = *amount;
+                let kernel = [0.0, -a, 0.0, -a, 1.0 + 4.0 * a, -a, 0.0, -a, 0.0];
+                Self::convolve(img, &kernel, 3, 0.0, EdgeMode::Clamp, cancel)
+            }
+            FilterOperation::UnsharpMask {
+                radius,
+                amount,
+                threshold,
+            } => Self::unsharp_mask(img, *radius, *amount, *threshold, cancel),
+            FilterOperation::EdgeDetect { method, low, high } => {
+                Self::edge_detect(img, *method, *low, *high)
+            }
+            FilterOperation::Emboss { amount } => {
+                // Convolving the luminance rather than each color keeps the
+                // relief grey around the mid-grey bias.
+                let mut grey = img.to_rgba8();
+                for pixel in grey.pixels_mut() {
+                    let luminance =
+                        (Self::sort_key(pixel, SortKey::Luminance) * 255.0).round() as u8;
+                    pixel.0 = [luminance, luminance, luminance, pixel[3]];
+                }
+                let a = *amount;
+                let kernel = [-a, -a, 0.0, -a, 0.0, a, 0.0, a, a];
+                let grey = DynamicImage::ImageRgba8(grey);
+                Self::convolve(&grey, &kernel, 3, 0.5, EdgeMode::Clamp, cancel)
+            }
+            FilterOperation::Convolve {
+                kernel,
+                normalize,
+                bias,
+                edge,
+            } => {
+                let width = kernel.first().map_or(0, Vec::len);
+                if width % 2 == 0
+                    || kernel.len() % 2 == 0
+                    || kernel.iter().any(|row| row.len() != width)
+                {
+                    return Err(anyhow::anyhow!(
+                        "Kernel must be a rectangle with an odd number of rows and columns"
+                    ));
+                }
+                let mut kernel: Vec<f32> = kernel.concat();
+                let sum: f32 = kernel.iter().sum();
+                if *normalize && sum != 0.0 {
+                    kernel.iter_mut().for_each(|weight| *weight /= sum);
+                }
+                Self::convolve(img, &kernel, width, *bias, *edge, cancel)
+            }
+            FilterOperation::MotionBlur {
+                angle,
+                length,
+                edge,
+            } => {
+                let (sin, cos) = angle.to_radians().sin_cos();
+                let steps = (length.abs().ceil() as usize).clamp(1, MAX_BLUR_SAMPLES);
+                Self::path_blur(img, *edge, cancel, |x, y, samples| {
+                    for step in 0..=steps {
+                        let offset = (step as f32 / steps as f32 - 0.5) * length;
+                        samples.push((x + offset * cos, y + offset * sin));
+                    }
+                })
+            }
+            FilterOperation::RadialBlur { angle, center } => {
+                let (cx, cy) = Self::blur_center(img, center);
+                let spin = angle.to_radians();
+                Self::path_blur(img, EdgeMode::Clamp, cancel, |x, y, samples| {
+                    let (dx, dy) = (x - cx, y - cy);
+                    let arc = (dx * dx + dy * dy).sqrt() * spin.abs();
+                    let steps = (arc.ceil() as usize).clamp(1, MAX_BLUR_SAMPLES);
+                    for step in 0..=steps {
+                        let (sin, cos) = ((step as f32 / steps as f32 - 0.5) * spin).sin_cos();
+                        samples.push((cx + dx * cos - dy * sin, cy + dx * sin + dy * cos));
+                    }
+                })
+            }
+            FilterOperation::ZoomBlur { amount, center } => {
+                let (cx, cy) = Self::blur_center(img, center);
+                let amount = amount.clamp(0.0, 1.0);
+                Self::path_blur(img, EdgeMode::Clamp, cancel, |x, y, samples| {
+                    let (dx, dy) = (cx - x, cy - y);
+                    let streak = (dx * dx + dy * dy).sqrt() * amount;
+                    let steps = (streak.ceil() as usize).clamp(1, MAX_BLUR_SAMPLES);
+                    for step in 0..=steps {
+                        let t = step as f32 / steps as f32 * amount;
+                        samples.push((x + dx * t, y + dy * t));
+                    }
+                })
+            }
+            FilterOperation::TiltShift {
+                focus,
+                width,
+                transition,
+                radius,
+            } => Self::tilt_shift(img, *focus, *width, *transition, *radius, cancel),
+            FilterOperation::Bloom {
+                threshold,
+                radii,
+                tint,
+                blend_mode,
+                intensity,
+            } => {
+                let tint = tint.as_deref().map(Self::parse_color).transpose()?;
+                Self::bloom(
+                    img,
+                    *threshold,
+                    radii,
+                    tint,
+                    *blend_mode,
+                    *intensity,
+                    cancel,
+                )
+            }
         }
     }
 
-    fn overlay_with_opacity(
+    fn overlay_blended(
         base: &mut DynamicImage,
         overlay: &DynamicImage,
         x: i32,
         y: i32,
         opacity: f32,
-    ) {
+        blend_mode: BlendMode,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
         let base_rgba = base.to_rgba8();
         let overlay_rgba = overlay.to_rgba8();
         let (overlay_width, overlay_height) = overlay_rgba.dimensions();
@@ -159,6 +556,7 @@ impl ImageProcessor {
         let mut result = base_rgba;
 
         for ox in 0..overlay_width {
+            cancel.check()?;
             for oy in 0..overlay_height {
                 let base_x = x + ox as i32;
                 let base_y = y + oy as i32;
@@ -169,53 +567,98 @@ impl ImageProcessor {
                     && base_y < result.height() as i32
                 {
                     let overlay_pixel = overlay_rgba.get_pixel(ox, oy);
-                    let base_pixel = result.get_pixel(base_x as u32, base_y as u32);
+                    let base_pixel = result.get_pixel_mut(base_x as u32, base_y as u32);
 
                     if overlay_pixel[3] > 0 {
                         let alpha = (overlay_pixel[3] as f32 / 255.0) * opacity;
-
-                        let blended = [
-                            (base_pixel[0] as f32 * (1.0 - alpha) + overlay_pixel[0] as f32 * alpha)
-                                as u8,
-                            (base_pixel[1] as f32 * (1.0 - alpha) + overlay_pixel[1] as f32 * alpha)
-                                as u8,
-                            (base_pixel[2] as f32 * (1.0 - alpha) + overlay_pixel[2] as f32 * alpha)
-                                as u8,
-                            (base_pixel[3] as f32 * (1.0 - alpha) + overlay_pixel[3] as f32 * alpha)
-                                as u8,
-                        ];
-
-                        result.put_pixel(base_x as u32, base_y as u32, Rgba(blended));
+                        let top = [0, 1, 2].map(|channel| overlay_pixel[channel] as f32 / 255.0);
+                        Self::composite(base_pixel, top, blend_mode, alpha);
+                        base_pixel[3] = (base_pixel[3] as f32 * (1.0 - alpha)
+                            + overlay_pixel[3] as f32 * alpha)
+                            as u8;
                     }
                 }
             }
         }
 
         *base = DynamicImage::ImageRgba8(result);
+        Ok(())
     }
 
     // Реализации фильтров остаются такими же, как в предыдущей версии
-    fn add_grain(img: &DynamicImage, intensity: f32) -> DynamicImage {
-        use rand::Rng;
-        let mut rng = rand::thread_rng();
-
+    /// Noise is generated on a grid `size` times coarser than the image and
+    /// bilinearly upsampled, so larger sizes give softer, bigger grains.
+    #[allow(clippy::too_many_arguments)]
+    fn add_grain(
+        img: &DynamicImage,
+        intensity: f32,
+        distribution: GrainDistribution,
+        size: f32,
+        monochrome: bool,
+        response: f32,
+        rng: &mut ChaCha12Rng,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
         let mut result = img.to_rgba8();
         let (width, height) = result.dimensions();
+        let size = size.max(1.0);
+        let response = response.clamp(0.0, 1.0);
+        let channels = if monochrome { 1 } else { 3 };
+        let grid_width = (width as f32 / size).ceil().max(1.0) as usize;
+        let grid_height = (height as f32 / size).ceil().max(1.0) as usize;
+
+        let mut grid = Vec::with_capacity(grid_width * grid_height * channels);
+        for _ in 0..grid_width * grid_height * channels {
+            grid.push(Self::grain_sample(rng, distribution));
+        }
+        let grid_value = |x: usize, y: usize, channel: usize| {
+            grid[(x.min(grid_width - 1) * grid_height + y.min(grid_height - 1)) * channels
+                + channel]
+        };
 
         for x in 0..width {
+            cancel.check()?;
+            let gx = x as f32 / size;
+            let (x0, fx) = (gx.floor() as usize, gx.fract());
             for y in 0..height {
+                let gy = y as f32 / size;
+                let (y0, fy) = (gy.floor() as usize, gy.fract());
                 let mut pixel = *result.get_pixel(x, y);
-                let noise = (rng.r#gen::<f32>() - 0.5) * intensity * 255.0;
 
-                pixel[0] = (pixel[0] as f32 + noise).clamp(0.0, 255.0) as u8;
-                pixel[1] = (pixel[1] as f32 + noise).clamp(0.0, 255.0) as u8;
-                pixel[2] = (pixel[2] as f32 + noise).clamp(0.0, 255.0) as u8;
+                let luminance = Self::sort_key(&pixel, SortKey::Luminance);
+                let weight = 1.0 - response * (2.0 * luminance - 1.0).powi(2);
+                let scale = intensity * weight * 255.0;
+
+                for channel in 0..3 {
+                    let grain = channel.min(channels - 1);
+                    let top =
+                        grid_value(x0, y0, grain) * (1.0 - fx) + grid_value(x0 + 1, y0, grain) * fx;
+                    let bottom = grid_value(x0, y0 + 1, grain) * (1.0 - fx)
+                        + grid_value(x0 + 1, y0 + 1, grain) * fx;
+                    let noise = (top * (1.0 - fy) + bottom * fy) * scale;
+                    pixel[channel] = (pixel[channel] as f32 + noise).clamp(0.0, 255.0) as u8;
+                }
 
                 result.put_pixel(x, y, pixel);
             }
         }
 
-        DynamicImage::ImageRgba8(result)
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    /// A noise value with the standard deviation of a uniform value in
+    /// -0.5..0.5.
+    fn grain_sample(rng: &mut ChaCha12Rng, distribution: GrainDistribution) -> f32 {
+        match distribution {
+            GrainDistribution::Uniform => rng.r#gen::<f32>() - 0.5,
+            GrainDistribution::Gaussian => {
+                // Box-Muller; `1.0 - gen` keeps the logarithm's argument above zero.
+                let u1 = 1.0 - rng.r#gen::<f32>();
+                let u2 = rng.r#gen::<f32>();
+                let normal = (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos();
+                normal / 12f32.sqrt()
+            }
+        }
     }
 
     fn double_vision(
@@ -223,12 +666,14 @@ impl ImageProcessor {
         offset_x: i32,
         offset_y: i32,
         opacity: f32,
-    ) -> DynamicImage {
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
         let mut result = img.clone().to_rgba8();
         let original = img.to_rgba8();
         let (width, height) = result.dimensions();
 
         for x in 0..width {
+            cancel.check()?;
             for y in 0..height {
                 let src_x = x as i32 + offset_x;
                 let src_y = y as i32 + offset_y;
@@ -252,132 +697,1346 @@ impl ImageProcessor {
             }
         }
 
-        DynamicImage::ImageRgba8(result)
+        Ok(DynamicImage::ImageRgba8(result))
     }
 
-    fn vignette(img: &DynamicImage, intensity: f32) -> DynamicImage {
-        let mut result = img.to_rgba8();
+    fn chromatic_aberration(
+        img: &DynamicImage,
+        offsets: [[f32; 2]; 3],
+        radial: f32,
+        edge: EdgeMode,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        let original = img.to_rgba8();
+        let mut result = original.clone();
         let (width, height) = result.dimensions();
         let center_x = width as f32 / 2.0;
         let center_y = height as f32 / 2.0;
-        let max_dist = (center_x * center_x + center_y * center_y).sqrt();
+        let max_dist = (center_x * center_x + center_y * center_y).sqrt().max(1.0);
+        // Radial displacement per pixel of distance from the center.
+        let radial = [radial / max_dist, 0.0, -radial / max_dist];
 
         for x in 0..width {
+            cancel.check()?;
             for y in 0..height {
-                let dx = center_x - x as f32;
-                let dy = center_y - y as f32;
-                let dist = (dx * dx + dy * dy).sqrt();
-                let factor = 1.0 - (dist / max_dist) * intensity;
-
+                let dx = x as f32 - center_x;
+                let dy = y as f32 - center_y;
                 let mut pixel = *result.get_pixel(x, y);
-                pixel[0] = (pixel[0] as f32 * factor) as u8;
-                pixel[1] = (pixel[1] as f32 * factor) as u8;
-                pixel[2] = (pixel[2] as f32 * factor) as u8;
+
+                for channel in 0..3 {
+                    // A channel moved by an offset shows what was offset pixels back.
+                    let [offset_x, offset_y] = offsets[channel];
+                    let src_x = x as f32 - offset_x - dx * radial[channel];
+                    let src_y = y as f32 - offset_y - dy * radial[channel];
+                    pixel[channel] = Self::sample_channel(&original, src_x, src_y, channel, edge);
+                }
 
                 result.put_pixel(x, y, pixel);
             }
         }
 
-        DynamicImage::ImageRgba8(result)
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    /// Bilinearly samples one channel at a fractional position.
+    fn sample_channel(img: &RgbaImage, x: f32, y: f32, channel: usize, edge: EdgeMode) -> u8 {
+        let (width, height) = img.dimensions();
+        let x = x.clamp(-MAX_SAMPLE_COORDINATE, MAX_SAMPLE_COORDINATE);
+        let y = y.clamp(-MAX_SAMPLE_COORDINATE, MAX_SAMPLE_COORDINATE);
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+
+        let value = |x: i64, y: i64| match (
+            Self::edge_coordinate(x, width, edge),
+            Self::edge_coordinate(y, height, edge),
+        ) {
+            (Some(x), Some(y)) => img.get_pixel(x, y)[channel] as f32,
+            _ => 0.0,
+        };
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = value(x0, y0) * (1.0 - fx) + value(x0 + 1, y0) * fx;
+        let bottom = value(x0, y0 + 1) * (1.0 - fx) + value(x0 + 1, y0 + 1) * fx;
+
+        (top * (1.0 - fy) + bottom * fy).round().clamp(0.0, 255.0) as u8
+    }
+
+    /// Maps a coordinate outside `0..size` back into the image, or `None` for
+    /// `EdgeMode::Black`.
+    fn edge_coordinate(coordinate: i64, size: u32, edge: EdgeMode) -> Option<u32> {
+        let size = size as i64;
+        if (0..size).contains(&coordinate) {
+            return Some(coordinate as u32);
+        }
+        let mapped = match edge {
+            EdgeMode::Clamp => coordinate.clamp(0, size - 1),
+            EdgeMode::Wrap => coordinate.rem_euclid(size),
+            EdgeMode::Mirror => {
+                let period = coordinate.rem_euclid(2 * size);
+                if period < size {
+                    period
+                } else {
+                    2 * size - 1 - period
+                }
+            }
+            EdgeMode::Black => return None,
+        };
+        Some(mapped as u32)
     }
 
-    fn sepia(img: &DynamicImage) -> DynamicImage {
+    #[allow(clippy::too_many_arguments)]
+    fn pixel_sort(
+        img: &DynamicImage,
+        key: SortKey,
+        direction: Direction,
+        threshold: [f32; 2],
+        reverse: bool,
+        max_run: Option<u32>,
+        rng: &mut ChaCha12Rng,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
         let mut result = img.to_rgba8();
         let (width, height) = result.dimensions();
+        let (lines, length) = match direction {
+            Direction::Horizontal => (height, width as usize),
+            Direction::Vertical => (width, height as usize),
+        };
+        let [low, high] = threshold;
+
+        for line in 0..lines {
+            cancel.check()?;
+            let position = |i: usize| match direction {
+                Direction::Horizontal => (i as u32, line),
+                Direction::Vertical => (line, i as u32),
+            };
+            let pixels: Vec<Rgba<u8>> = (0..length)
+                .map(|i| {
+                    let (x, y) = position(i);
+                    *result.get_pixel(x, y)
+                })
+                .collect();
+            let keys: Vec<f32> = pixels.iter().map(|p| Self::sort_key(p, key)).collect();
+            let in_run = |i: usize| keys[i] >= low && keys[i] <= high;
+
+            let mut start = 0;
+            while start < length {
+                if !in_run(start) {
+                    start += 1;
+                    continue;
+                }
+                let limit = match max_run {
+                    Some(max_run) => {
+                        (start + rng.gen_range(1..=max_run.max(1)) as usize).min(length)
+                    }
+                    None => length,
+                };
+                let mut end = start;
+                while end < limit && in_run(end) {
+                    end += 1;
+                }
 
-        for x in 0..width {
-            for y in 0..height {
-                let mut pixel = *result.get_pixel(x, y);
-                let r = pixel[0] as f32;
-                let g = pixel[1] as f32;
-                let b = pixel[2] as f32;
+                let mut run: Vec<usize> = (start..end).collect();
+                run.sort_by(|a, b| keys[*a].total_cmp(&keys[*b]));
+                if reverse {
+                    run.reverse();
+                }
+                for (i, source) in (start..end).zip(run) {
+                    let (x, y) = position(i);
+                    result.put_pixel(x, y, pixels[source]);
+                }
+                start = end;
+            }
+        }
 
-                let tr = (0.393 * r + 0.769 * g + 0.189 * b).min(255.0);
-                let tg = (0.349 * r + 0.686 * g + 0.168 * b).min(255.0);
-                let tb = (0.272 * r + 0.534 * g + 0.131 * b).min(255.0);
+        Ok(DynamicImage::ImageRgba8(result))
+    }
 
-                pixel[0] = tr as u8;
-                pixel[1] = tg as u8;
-                pixel[2] = tb as u8;
+    /// Luminance, hue or saturation of a pixel, from 0 to 1.
+    fn sort_key(pixel: &Rgba<u8>, key: SortKey) -> f32 {
+        let r = pixel[0] as f32 / 255.0;
+        let g = pixel[1] as f32 / 255.0;
+        let b = pixel[2] as f32 / 255.0;
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+
+        match key {
+            SortKey::Luminance => 0.299 * r + 0.587 * g + 0.114 * b,
+            SortKey::Saturation if max > 0.0 => delta / max,
+            SortKey::Saturation => 0.0,
+            SortKey::Hue if delta == 0.0 => 0.0,
+            SortKey::Hue => {
+                let hue = if max == r {
+                    ((g - b) / delta).rem_euclid(6.0)
+                } else if max == g {
+                    (b - r) / delta + 2.0
+                } else {
+                    (r - g) / delta + 4.0
+                };
+                hue / 6.0
+            }
+        }
+    }
 
-                result.put_pixel(x, y, pixel);
+    fn slice_displacement(
+        img: &DynamicImage,
+        slices: u32,
+        max_offset: u32,
+        edge: EdgeMode,
+        rng: &mut ChaCha12Rng,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        let original = img.to_rgba8();
+        let mut result = original.clone();
+        let (width, height) = result.dimensions();
+        if height < 2 {
+            return Ok(DynamicImage::ImageRgba8(result));
+        }
+
+        let mut cuts: Vec<u32> = (1..slices.clamp(1, height))
+            .map(|_| rng.gen_range(1..height))
+            .collect();
+        cuts.extend([0, height]);
+        cuts.sort_unstable();
+        cuts.dedup();
+
+        for slice in cuts.windows(2) {
+            cancel.check()?;
+            let offset = rng.gen_range(-(max_offset as i64)..=max_offset as i64);
+            for y in slice[0]..slice[1] {
+                for x in 0..width {
+                    let pixel = match Self::edge_coordinate(x as i64 - offset, width, edge) {
+                        Some(src_x) => *original.get_pixel(src_x, y),
+                        None => Rgba([0, 0, 0, 255]),
+                    };
+                    result.put_pixel(x, y, pixel);
+                }
             }
         }
 
-        DynamicImage::ImageRgba8(result)
+        Ok(DynamicImage::ImageRgba8(result))
     }
 
-    fn brightness(img: &DynamicImage, value: f32) -> DynamicImage {
-        let mut result = img.to_rgba8();
-        colorops::brighten_in_place(&mut result, value as i32);
-        DynamicImage::ImageRgba8(result)
+    fn block_corruption(
+        img: &DynamicImage,
+        block_size: u32,
+        amount: f32,
+        rng: &mut ChaCha12Rng,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        let original = img.to_rgba8();
+        let mut result = original.clone();
+        let (width, height) = result.dimensions();
+        let size = block_size.max(1);
+        let blocks_x = width.div_ceil(size);
+        let blocks_y = height.div_ceil(size);
+
+        for block_y in 0..blocks_y {
+            cancel.check()?;
+            for block_x in 0..blocks_x {
+                if rng.r#gen::<f32>() >= amount {
+                    continue;
+                }
+                let (x0, y0) = (block_x * size, block_y * size);
+                let block = (x0..(x0 + size).min(width))
+                    .flat_map(|x| (y0..(y0 + size).min(height)).map(move |y| (x, y)));
+
+                match rng.gen_range(0..4) {
+                    // A block decoded in the wrong place.
+                    0 => {
+                        let src_x0 = rng.gen_range(0..blocks_x) * size;
+                        let src_y0 = rng.gen_range(0..blocks_y) * size;
+                        for (x, y) in block {
+                            let src_x = (src_x0 + x - x0).min(width - 1);
+                            let src_y = (src_y0 + y - y0).min(height - 1);
+                            result.put_pixel(x, y, *original.get_pixel(src_x, src_y));
+                        }
+                    }
+                    // Only the DC coefficient survived, with a drift.
+                    1 => {
+                        let pixels: Vec<_> = block.clone().collect();
+                        let mut sum = [0u32; 3];
+                        for &(x, y) in &pixels {
+                            let pixel = original.get_pixel(x, y);
+                            for channel in 0..3 {
+                                sum[channel] += pixel[channel] as u32;
+                            }
+                        }
+                        let drift = rng.gen_range(-48..=48);
+                        let mut flat = *original.get_pixel(x0, y0);
+                        for channel in 0..3 {
+                            let average = (sum[channel] / pixels.len() as u32) as i32;
+                            flat[channel] = (average + drift).clamp(0, 255) as u8;
+                        }
+                        for (x, y) in pixels {
+                            result.put_pixel(x, y, flat);
+                        }
+                    }
+                    // The first row smeared down the block.
+                    2 => {
+                        for (x, y) in block {
+                            result.put_pixel(x, y, *original.get_pixel(x, y0));
+                        }
+                    }
+                    // Color channels swapped.
+                    _ => {
+                        for (x, y) in block {
+                            let mut pixel = *original.get_pixel(x, y);
+                            pixel.0.swap(0, 2);
+                            pixel.0.swap(1, 2);
+                            result.put_pixel(x, y, pixel);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(DynamicImage::ImageRgba8(result))
     }
 
-    fn contrast(img: &DynamicImage, value: f32) -> DynamicImage {
+    fn threshold(
+        img: &DynamicImage,
+        level: f32,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
         let mut result = img.to_rgba8();
-        colorops::contrast_in_place(&mut result, value);
-        DynamicImage::ImageRgba8(result)
+        let (width, height) = result.dimensions();
+
+        for x in 0..width {
+            cancel.check()?;
+            for y in 0..height {
+                let pixel = result.get_pixel_mut(x, y);
+                let value = if Self::sort_key(pixel, SortKey::Luminance) >= level {
+                    255
+                } else {
+                    0
+                };
+                pixel[0] = value;
+                pixel[1] = value;
+                pixel[2] = value;
+            }
+        }
+
+        Ok(DynamicImage::ImageRgba8(result))
     }
 
-    fn saturation(img: &DynamicImage, value: f32) -> DynamicImage {
-        // Упрощенная реализация насыщенности
+    fn posterize(
+        img: &DynamicImage,
+        levels: u32,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
         let mut result = img.to_rgba8();
         let (width, height) = result.dimensions();
+        let steps = (levels.clamp(2, 256) - 1) as f32;
 
         for x in 0..width {
+            cancel.check()?;
             for y in 0..height {
-                let mut pixel = *result.get_pixel(x, y);
-                let r = pixel[0] as f32;
-                let g = pixel[1] as f32;
-                let b = pixel[2] as f32;
+                let pixel = result.get_pixel_mut(x, y);
+                for channel in 0..3 {
+                    let value = (pixel[channel] as f32 / 255.0 * steps).round() / steps;
+                    pixel[channel] = (value * 255.0).round() as u8;
+                }
+            }
+        }
 
-                let gray = 0.299 * r + 0.587 * g + 0.114 * b;
+        Ok(DynamicImage::ImageRgba8(result))
+    }
 
-                pixel[0] = (gray + (r - gray) * value).clamp(0.0, 255.0) as u8;
-                pixel[1] = (gray + (g - gray) * value).clamp(0.0, 255.0) as u8;
-                pixel[2] = (gray + (b - gray) * value).clamp(0.0, 255.0) as u8;
+    fn ordered_dither(
+        img: &DynamicImage,
+        matrix_size: u32,
+        palette: &Palette,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        if !matches!(matrix_size, 2 | 4 | 8) {
+            return Err(anyhow::anyhow!("Bayer matrix size must be 2, 4 or 8"));
+        }
+        let mut result = img.to_rgba8();
+        let (width, height) = result.dimensions();
+        let colors = Self::resolve_palette(&result, palette)?;
+        let matrix = Self::bayer_matrix(matrix_size);
+        // About the distance between neighbouring palette colors, assuming
+        // they are spread evenly over the RGB cube.
+        let spread = 255.0 / ((colors.len() as f32).cbrt() - 1.0).max(1.0);
 
-                result.put_pixel(x, y, pixel);
+        for x in 0..width {
+            cancel.check()?;
+            for y in 0..height {
+                let index = (y % matrix_size * matrix_size + x % matrix_size) as usize;
+                let offset = ((matrix[index] as f32 + 0.5) / matrix.len() as f32 - 0.5) * spread;
+                let pixel = result.get_pixel_mut(x, y);
+                let target = [0, 1, 2].map(|channel| pixel[channel] as f32 + offset);
+                let color = Self::nearest_color(&colors, target);
+                pixel[0] = color[0];
+                pixel[1] = color[1];
+                pixel[2] = color[2];
             }
         }
 
-        DynamicImage::ImageRgba8(result)
+        Ok(DynamicImage::ImageRgba8(result))
     }
 
-    fn hue_rotate(img: &DynamicImage, degrees: f32) -> DynamicImage {
-        let mut result = img.to_rgba8();
-        colorops::huerotate_in_place(&mut result, degrees as i32);
-        DynamicImage::ImageRgba8(result)
+    /// Threshold ranks of a `size` x `size` Bayer matrix, row by row.
+    fn bayer_matrix(size: u32) -> Vec<u32> {
+        let mut matrix = vec![0];
+        let mut current = 1;
+        while current < size {
+            let next = current * 2;
+            let mut grown = vec![0; (next * next) as usize];
+            for y in 0..next {
+                for x in 0..next {
+                    let base = 4 * matrix[((y % current) * current + x % current) as usize];
+                    let quadrant = match (x < current, y < current) {
+                        (true, true) => 0,
+                        (false, true) => 2,
+                        (true, false) => 3,
+                        (false, false) => 1,
+                    };
+                    grown[(y * next + x) as usize] = base + quadrant;
+                }
+            }
+            matrix = grown;
+            current = next;
+        }
+        matrix
     }
 
-    fn draw_text(
+    fn error_diffusion(
         img: &DynamicImage,
-        content: &str,
-        font_path: &Path,
-        size: f32,
-        color: &str,
-        x: i32,
-        y: i32,
-        stroke: Option<&Stroke>,
-        shadow: Option<&Shadow>,
+        kernel: DiffusionKernel,
+        palette: &Palette,
+        cancel: &CancellationToken,
     ) -> Result<DynamicImage> {
         let mut result = img.to_rgba8();
+        let (width, height) = result.dimensions();
+        let colors = Self::resolve_palette(&result, palette)?;
+        // Neighbours as (dx, dy, weight), and the sum the weights are divided by.
+        let (neighbours, divisor): (&[(i64, i64, f32)], f32) = match kernel {
+            DiffusionKernel::FloydSteinberg => {
+                (&[(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)], 16.0)
+            }
+            // Only passes on three quarters of the error, which keeps more contrast.
+            DiffusionKernel::Atkinson => (
+                &[
+                    (1, 0, 1.0),
+                    (2, 0, 1.0),
+                    (-1, 1, 1.0),
+                    (0, 1, 1.0),
+                    (1, 1, 1.0),
+                    (0, 2, 1.0),
+                ],
+                8.0,
+            ),
+            DiffusionKernel::Jarvis => (
+                &[
+                    (1, 0, 7.0),
+                    (2, 0, 5.0),
+                    (-2, 1, 3.0),
+                    (-1, 1, 5.0),
+                    (0, 1, 7.0),
+                    (1, 1, 5.0),
+                    (2, 1, 3.0),
+                    (-2, 2, 1.0),
+                    (-1, 2, 3.0),
+                    (0, 2, 5.0),
+                    (1, 2, 3.0),
+                    (2, 2, 1.0),
+                ],
+                48.0,
+            ),
+        };
 
-        let font_path = Path::new("assets/fonts/").join(font_path);
-        let font_data = std::fs::read(font_path)?;
-        let font = ab_glyph::FontArc::try_from_vec(font_data)?;
+        let mut values: Vec<[f32; 3]> = result
+            .pixels()
+            .map(|pixel| [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32])
+            .collect();
+
+        // Error only flows right and down, so this goes row by row.
+        for y in 0..height {
+            cancel.check()?;
+            for x in 0..width {
+                let index = (y * width + x) as usize;
+                let value = values[index];
+                let color = Self::nearest_color(&colors, value);
+                let pixel = result.get_pixel_mut(x, y);
+                pixel[0] = color[0];
+                pixel[1] = color[1];
+                pixel[2] = color[2];
+
+                let error = [0, 1, 2].map(|channel| value[channel] - color[channel] as f32);
+                for &(dx, dy, weight) in neighbours {
+                    let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                    if nx < 0 || nx >= width as i64 || ny >= height as i64 {
+                        continue;
+                    }
+                    let neighbour = &mut values[(ny * width as i64 + nx) as usize];
+                    for channel in 0..3 {
+                        neighbour[channel] += error[channel] * weight / divisor;
+                    }
+                }
+            }
+        }
 
-        let scale = size;
-        let color = Self::parse_color(color)?;
+        Ok(DynamicImage::ImageRgba8(result))
+    }
 
-        if let Some(shadow) = shadow {
-            let shadow_color = Self::parse_color(&shadow.color)?;
-            drawing::draw_text_mut(
-                &mut result,
-                shadow_color,
-                x + shadow.offset_x,
-                y + shadow.offset_y,
-                scale,
+    fn halftone(
+        img: &DynamicImage,
+        pattern: HalftonePattern,
+        cell_size: f32,
+        angle: f32,
+        mode: HalftoneMode,
+        cmyk_angles: &[f32; 4],
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        let original = img.to_rgba8();
+        let mut result = original.clone();
+        let (width, height) = result.dimensions();
+        let cell_size = cell_size.max(2.0);
+
+        for x in 0..width {
+            cancel.check()?;
+            for y in 0..height {
+                let pixel = result.get_pixel_mut(x, y);
+                match mode {
+                    HalftoneMode::Mono => {
+                        let inked =
+                            Self::halftone_ink(&original, x, y, cell_size, angle, pattern, |rgb| {
+                                1.0 - (0.299 * rgb[0] + 0.587 * rgb[1] + 0.114 * rgb[2])
+                            });
+                        let value = if inked { 0 } else { 255 };
+                        pixel[0] = value;
+                        pixel[1] = value;
+                        pixel[2] = value;
+                    }
+                    HalftoneMode::Cmyk => {
+                        let inks: [bool; 4] = std::array::from_fn(|ink| {
+                            Self::halftone_ink(
+                                &original,
+                                x,
+                                y,
+                                cell_size,
+                                cmyk_angles[ink],
+                                pattern,
+                                |rgb| Self::cmyk(rgb)[ink],
+                            )
+                        });
+                        // Cyan absorbs red, magenta green and yellow blue.
+                        for channel in 0..3 {
+                            pixel[channel] = if inks[channel] || inks[3] { 0 } else { 255 };
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    /// Whether the screen rotated by `angle` puts ink on pixel (`x`, `y`).
+    /// Each cell is sized to cover the share of its area that `coverage`
+    /// returns for the color at the cell's centre.
+    fn halftone_ink(
+        original: &RgbaImage,
+        x: u32,
+        y: u32,
+        cell_size: f32,
+        angle: f32,
+        pattern: HalftonePattern,
+        coverage: impl Fn([f32; 3]) -> f32,
+    ) -> bool {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+        // Position in screen space, where cells are one unit wide.
+        let u = (px * cos + py * sin) / cell_size;
+        let v = (py * cos - px * sin) / cell_size;
+        let (cell_u, cell_v) = (u.floor() + 0.5, v.floor() + 0.5);
+        let (du, dv) = (u - cell_u, v - cell_v);
+
+        let centre_x = (cell_u * cos - cell_v * sin) * cell_size - 0.5;
+        let centre_y = (cell_u * sin + cell_v * cos) * cell_size - 0.5;
+        let rgb = [0, 1, 2].map(|channel| {
+            Self::sample_channel(original, centre_x, centre_y, channel, EdgeMode::Clamp) as f32
+                / 255.0
+        });
+        let coverage = coverage(rgb).clamp(0.0, 1.0);
+
+        match pattern {
+            // A disc of ink up to half coverage, then a disc of paper around
+            // each corner, so dots join into a checkerboard smoothly.
+            HalftonePattern::Dot if coverage <= 0.5 => {
+                std::f32::consts::PI * (du * du + dv * dv) < coverage
+            }
+            HalftonePattern::Dot => {
+                let (cu, cv) = (0.5 - du.abs(), 0.5 - dv.abs());
+                std::f32::consts::PI * (cu * cu + cv * cv) >= 1.0 - coverage
+            }
+            HalftonePattern::Line => dv.abs() < coverage / 2.0,
+            HalftonePattern::Cross => {
+                let half_width = (1.0 - (1.0 - coverage).sqrt()) / 2.0;
+                du.abs() < half_width || dv.abs() < half_width
+            }
+        }
+    }
+
+    /// Converts RGB (0 to 1) to cyan, magenta, yellow and black ink amounts.
+    fn cmyk(rgb: [f32; 3]) -> [f32; 4] {
+        let black = 1.0 - rgb[0].max(rgb[1]).max(rgb[2]);
+        if black >= 1.0 {
+            return [0.0, 0.0, 0.0, 1.0];
+        }
+        let [c, m, y] = rgb.map(|value| (1.0 - value - black) / (1.0 - black));
+        [c, m, y, black]
+    }
+
+    fn apply_lut(
+        img: &DynamicImage,
+        lut: &Lut,
+        interpolation: LutInterpolation,
+        strength: f32,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        let mut result = img.to_rgba8();
+        let (width, height) = result.dimensions();
+        let strength = strength.clamp(0.0, 1.0);
+
+        for x in 0..width {
+            cancel.check()?;
+            for y in 0..height {
+                let pixel = result.get_pixel_mut(x, y);
+                let rgb = [0, 1, 2].map(|channel| pixel[channel] as f32 / 255.0);
+                let graded = lut.apply(rgb, interpolation);
+                for channel in 0..3 {
+                    let value = rgb[channel] + (graded[channel] - rgb[channel]) * strength;
+                    pixel[channel] = (value * 255.0).round().clamp(0.0, 255.0) as u8;
+                }
+            }
+        }
+
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    fn curves(
+        img: &DynamicImage,
+        rgb: &[[f32; 2]],
+        channels: [&Vec<[f32; 2]>; 3],
+        luma: &[[f32; 2]],
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        let master = Self::curve_table(rgb)?;
+        let tables = channels
+            .map(|points| Self::curve_table(points))
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        let luma = Self::curve_table(luma)?;
+        let mut result = img.to_rgba8();
+        let (width, height) = result.dimensions();
+
+        for x in 0..width {
+            cancel.check()?;
+            for y in 0..height {
+                let pixel = result.get_pixel_mut(x, y);
+                for channel in 0..3 {
+                    pixel[channel] = tables[channel][master[pixel[channel] as usize] as usize];
+                }
+                let luminance = Self::sort_key(pixel, SortKey::Luminance);
+                let index = (luminance * 255.0).round() as usize;
+                let shift = luma[index] as f32 - index as f32;
+                for channel in 0..3 {
+                    pixel[channel] = (pixel[channel] as f32 + shift).clamp(0.0, 255.0) as u8;
+                }
+            }
+        }
+
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    /// Tabulates a curve for every 8-bit value using monotone cubic
+    /// (Fritsch-Carlson) interpolation, which never overshoots between points.
+    /// Values outside the first and last point are held flat.
+    fn curve_table(points: &[[f32; 2]]) -> Result<[u8; 256]> {
+        let mut table = [0u8; 256];
+        if points.is_empty() {
+            for (value, entry) in table.iter_mut().enumerate() {
+                *entry = value as u8;
+            }
+            return Ok(table);
+        }
+        if points.len() < 2 {
+            return Err(anyhow::anyhow!("Curves need at least two points"));
+        }
+        let mut points = points.to_vec();
+        points.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        if points.windows(2).any(|pair| pair[1][0] <= pair[0][0]) {
+            return Err(anyhow::anyhow!("Curve points need distinct inputs"));
+        }
+
+        let slopes: Vec<f32> = points
+            .windows(2)
+            .map(|pair| (pair[1][1] - pair[0][1]) / (pair[1][0] - pair[0][0]))
+            .collect();
+        let mut tangents = vec![0.0; points.len()];
+        tangents[0] = slopes[0];
+        tangents[points.len() - 1] = slopes[slopes.len() - 1];
+        // Interior tangents are a weighted harmonic mean of the neighbouring
+        // slopes, and flat at local extremes.
+        for index in 1..points.len() - 1 {
+            let (before, after) = (slopes[index - 1], slopes[index]);
+            if before * after > 0.0 {
+                let h0 = points[index][0] - points[index - 1][0];
+                let h1 = points[index + 1][0] - points[index][0];
+                let (w0, w1) = (2.0 * h1 + h0, h1 + 2.0 * h0);
+                tangents[index] = (w0 + w1) / (w0 / before + w1 / after);
+            }
+        }
+
+        for (value, entry) in table.iter_mut().enumerate() {
+            let x = value as f32 / 255.0;
+            let y = if x <= points[0][0] {
+                points[0][1]
+            } else if x >= points[points.len() - 1][0] {
+                points[points.len() - 1][1]
+            } else {
+                let index = points.partition_point(|point| point[0] <= x) - 1;
+                let ([x0, y0], [x1, y1]) = (points[index], points[index + 1]);
+                let h = x1 - x0;
+                let t = (x - x0) / h;
+                let (t2, t3) = (t * t, t * t * t);
+                (2.0 * t3 - 3.0 * t2 + 1.0) * y0
+                    + (t3 - 2.0 * t2 + t) * h * tangents[index]
+                    + (-2.0 * t3 + 3.0 * t2) * y1
+                    + (t3 - t2) * h * tangents[index + 1]
+            };
+            *entry = (y * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+        Ok(table)
+    }
+
+    fn levels(
+        img: &DynamicImage,
+        input: [f32; 2],
+        gamma: f32,
+        output: [f32; 2],
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        if input[1] <= input[0] {
+            return Err(anyhow::anyhow!(
+                "input_white must be greater than input_black"
+            ));
+        }
+        if gamma <= 0.0 {
+            return Err(anyhow::anyhow!("gamma must be positive"));
+        }
+        let mut table = [0u8; 256];
+        for (value, entry) in table.iter_mut().enumerate() {
+            let t = ((value as f32 / 255.0 - input[0]) / (input[1] - input[0])).clamp(0.0, 1.0);
+            let y = output[0] + t.powf(1.0 / gamma) * (output[1] - output[0]);
+            *entry = (y * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+
+        let mut result = img.to_rgba8();
+        let (width, height) = result.dimensions();
+        for x in 0..width {
+            cancel.check()?;
+            for y in 0..height {
+                let pixel = result.get_pixel_mut(x, y);
+                for channel in 0..3 {
+                    pixel[channel] = table[pixel[channel] as usize];
+                }
+            }
+        }
+
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    /// Luminance below which and above which `clip` of the pixels lie.
+    fn auto_levels_range(img: &DynamicImage, clip: f32) -> (f32, f32) {
+        let mut histogram = [0usize; 256];
+        let pixels = img.to_rgba8();
+        for pixel in pixels.pixels() {
+            histogram[(Self::sort_key(pixel, SortKey::Luminance) * 255.0).round() as usize] += 1;
+        }
+        let clipped = (pixels.pixels().len() as f32 * clip.clamp(0.0, 0.5)) as usize;
+        // First value at which more than `clipped` pixels have been seen.
+        fn percentile(
+            histogram: &[usize; 256],
+            values: impl Iterator<Item = usize>,
+            clipped: usize,
+        ) -> usize {
+            let mut seen = 0;
+            for value in values {
+                seen += histogram[value];
+                if seen > clipped {
+                    return value;
+                }
+            }
+            0
+        }
+        let black = percentile(&histogram, 0..256, clipped);
+        let white = percentile(&histogram, (0..256).rev(), clipped);
+        if white <= black {
+            return (0.0, 1.0);
+        }
+        (black as f32 / 255.0, white as f32 / 255.0)
+    }
+
+    /// Stops are sorted by position; luminance before the first or after the
+    /// last stop takes that stop's color. Stop alpha scales the opacity.
+    fn gradient_map(
+        img: &DynamicImage,
+        mut stops: Vec<(f32, Rgba<u8>)>,
+        blend_mode: BlendMode,
+        opacity: f32,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        if stops.is_empty() {
+            return Err(anyhow::anyhow!("Gradient needs at least one stop"));
+        }
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let ramp: Vec<[f32; 4]> = (0..256)
+            .map(|value| {
+                let position = value as f32 / 255.0;
+                let after = stops.partition_point(|stop| stop.0 <= position);
+                let color = |index: usize| stops[index].1.0.map(|channel| channel as f32 / 255.0);
+                if after == 0 {
+                    return color(0);
+                }
+                if after == stops.len() {
+                    return color(after - 1);
+                }
+                let ((start, _), (end, _)) = (stops[after - 1], stops[after]);
+                let t = (position - start) / (end - start);
+                let (from, to) = (color(after - 1), color(after));
+                std::array::from_fn(|channel| from[channel] + (to[channel] - from[channel]) * t)
+            })
+            .collect();
+        let opacity = opacity.clamp(0.0, 1.0);
+        let mut result = img.to_rgba8();
+        let (width, height) = result.dimensions();
+
+        for x in 0..width {
+            cancel.check()?;
+            for y in 0..height {
+                let pixel = result.get_pixel_mut(x, y);
+                let luminance = Self::sort_key(pixel, SortKey::Luminance);
+                let mapped = ramp[(luminance * 255.0).round() as usize];
+                let top = [mapped[0], mapped[1], mapped[2]];
+                Self::composite(pixel, top, blend_mode, opacity * mapped[3]);
+            }
+        }
+
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    /// Lays `top` (colors 0 to 1) over the pixel with `mode`, mixing the
+    /// result in by `amount` (0 to 1). Alpha is kept.
+    fn composite(pixel: &mut Rgba<u8>, top: [f32; 3], mode: BlendMode, amount: f32) {
+        for channel in 0..3 {
+            let base = pixel[channel] as f32 / 255.0;
+            let blended = Self::blend(mode, base, top[channel]);
+            let value = base + (blended - base) * amount;
+            pixel[channel] = (value * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+
+    /// Blends one channel of `top` onto `base`, both 0 to 1. Soft light uses
+    /// the W3C compositing formula.
+    fn blend(mode: BlendMode, base: f32, top: f32) -> f32 {
+        match mode {
+            BlendMode::Normal => top,
+            BlendMode::Multiply => base * top,
+            BlendMode::Screen => 1.0 - (1.0 - base) * (1.0 - top),
+            BlendMode::Overlay if base < 0.5 => 2.0 * base * top,
+            BlendMode::Overlay => 1.0 - 2.0 * (1.0 - base) * (1.0 - top),
+            BlendMode::SoftLight if top <= 0.5 => base - (1.0 - 2.0 * top) * base * (1.0 - base),
+            BlendMode::SoftLight => {
+                let darkened = if base <= 0.25 {
+                    ((16.0 * base - 12.0) * base + 4.0) * base
+                } else {
+                    base.sqrt()
+                };
+                base + (2.0 * top - 1.0) * (darkened - base)
+            }
+            BlendMode::Add => (base + top).min(1.0),
+        }
+    }
+
+    /// Convolves the color channels with a kernel given row by row, `width`
+    /// entries per row. Alpha is kept.
+    fn convolve(
+        img: &DynamicImage,
+        kernel: &[f32],
+        width: usize,
+        bias: f32,
+        edge: EdgeMode,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        let original = img.to_rgba8();
+        let mut result = original.clone();
+        let (image_width, image_height) = result.dimensions();
+        let (half_x, half_y) = ((width / 2) as i64, (kernel.len() / width / 2) as i64);
+
+        for x in 0..image_width {
+            cancel.check()?;
+            for y in 0..image_height {
+                let mut sum = [bias * 255.0; 3];
+                for (index, weight) in kernel.iter().enumerate() {
+                    if *weight == 0.0 {
+                        continue;
+                    }
+                    let sx = x as i64 + (index % width) as i64 - half_x;
+                    let sy = y as i64 + (index / width) as i64 - half_y;
+                    let (Some(sx), Some(sy)) = (
+                        Self::edge_coordinate(sx, image_width, edge),
+                        Self::edge_coordinate(sy, image_height, edge),
+                    ) else {
+                        continue;
+                    };
+                    let source = original.get_pixel(sx, sy);
+                    for channel in 0..3 {
+                        sum[channel] += source[channel] as f32 * weight;
+                    }
+                }
+                let pixel = result.get_pixel_mut(x, y);
+                for channel in 0..3 {
+                    pixel[channel] = sum[channel].round().clamp(0.0, 255.0) as u8;
+                }
+            }
+        }
+
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    fn unsharp_mask(
+        img: &DynamicImage,
+        radius: f32,
+        amount: f32,
+        threshold: f32,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        let blurred = img.blur(radius.max(0.1)).to_rgba8();
+        let mut result = img.to_rgba8();
+        let (width, height) = result.dimensions();
+        let threshold = threshold.clamp(0.0, 1.0) * 255.0;
+
+        for x in 0..width {
+            cancel.check()?;
+            for y in 0..height {
+                let soft = blurred.get_pixel(x, y);
+                let pixel = result.get_pixel_mut(x, y);
+                for channel in 0..3 {
+                    let difference = pixel[channel] as f32 - soft[channel] as f32;
+                    if difference.abs() < threshold {
+                        continue;
+                    }
+                    let value = pixel[channel] as f32 + difference * amount;
+                    pixel[channel] = value.round().clamp(0.0, 255.0) as u8;
+                }
+            }
+        }
+
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    fn edge_detect(
+        img: &DynamicImage,
+        method: EdgeMethod,
+        low: f32,
+        high: f32,
+    ) -> Result<DynamicImage> {
+        let gray = img.to_luma8();
+        let edges = match method {
+            EdgeMethod::Sobel => {
+                let gradients = imageproc::gradients::sobel_gradients(&gray);
+                image::GrayImage::from_fn(gray.width(), gray.height(), |x, y| {
+                    image::Luma([gradients.get_pixel(x, y)[0].min(255) as u8])
+                })
+            }
+            EdgeMethod::Canny => {
+                if !(0.0..=high).contains(&low) {
+                    return Err(anyhow::anyhow!("Canny thresholds need 0 <= low <= high"));
+                }
+                // Thresholds are relative to the strongest possible Sobel response.
+                imageproc::edges::canny(&gray, low * 1020.0, high * 1020.0)
+            }
+        };
+
+        let mut result = img.to_rgba8();
+        for (pixel, edge) in result.pixels_mut().zip(edges.pixels()) {
+            pixel[0] = edge[0];
+            pixel[1] = edge[0];
+            pixel[2] = edge[0];
+        }
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    /// Replaces every pixel with the average of bilinear samples taken where
+    /// `path` puts them for that pixel.
+    fn path_blur(
+        img: &DynamicImage,
+        edge: EdgeMode,
+        cancel: &CancellationToken,
+        path: impl Fn(f32, f32, &mut Vec<(f32, f32)>),
+    ) -> Result<DynamicImage> {
+        let original = img.to_rgba8();
+        let mut result = original.clone();
+        let (width, height) = result.dimensions();
+        let mut samples = Vec::with_capacity(MAX_BLUR_SAMPLES + 1);
+
+        for x in 0..width {
+            cancel.check()?;
+            for y in 0..height {
+                samples.clear();
+                path(x as f32, y as f32, &mut samples);
+                let mut sum = [0.0f32; 4];
+                for &(sx, sy) in &samples {
+                    for (channel, total) in sum.iter_mut().enumerate() {
+                        *total += Self::sample_channel(&original, sx, sy, channel, edge) as f32;
+                    }
+                }
+                let pixel = result.get_pixel_mut(x, y);
+                for channel in 0..4 {
+                    pixel[channel] = (sum[channel] / samples.len() as f32).round() as u8;
+                }
+            }
+        }
+
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    fn blur_center(img: &DynamicImage, center: &[f32; 2]) -> (f32, f32) {
+        (
+            center[0] * img.width() as f32,
+            center[1] * img.height() as f32,
+        )
+    }
+
+    /// Blends between the image and copies blurred at a third, two thirds
+    /// and all of `radius`, so the blur grows smoothly away from the band.
+    fn tilt_shift(
+        img: &DynamicImage,
+        focus: f32,
+        width: f32,
+        transition: f32,
+        radius: f32,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        let mut levels = vec![img.to_rgba8()];
+        for level in 1..=3 {
+            cancel.check()?;
+            levels.push(img.blur(radius * level as f32 / 3.0).to_rgba8());
+        }
+        let mut result = levels[0].clone();
+        let (image_width, image_height) = result.dimensions();
+
+        for y in 0..image_height {
+            cancel.check()?;
+            let distance = (y as f32 / image_height as f32 - focus).abs() - width / 2.0;
+            let strength = (distance / transition.max(f32::EPSILON)).clamp(0.0, 1.0) * 3.0;
+            let lower = (strength.floor() as usize).min(2);
+            let t = strength - lower as f32;
+            for x in 0..image_width {
+                let (from, to) = (
+                    levels[lower].get_pixel(x, y),
+                    levels[lower + 1].get_pixel(x, y),
+                );
+                let pixel = result.get_pixel_mut(x, y);
+                for channel in 0..4 {
+                    let value =
+                        from[channel] as f32 + (to[channel] as f32 - from[channel] as f32) * t;
+                    pixel[channel] = value.round() as u8;
+                }
+            }
+        }
+
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    /// Highlights fade in over the first half of the range above `threshold`
+    /// rather than switching on, so the glow has no hard edge.
+    fn bloom(
+        img: &DynamicImage,
+        threshold: f32,
+        radii: &[f32],
+        tint: Option<Rgba<u8>>,
+        blend_mode: BlendMode,
+        intensity: f32,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        if radii.is_empty() {
+            return Err(anyhow::anyhow!("Bloom needs at least one radius"));
+        }
+        let mut result = img.to_rgba8();
+        let (width, height) = result.dimensions();
+        let knee = ((1.0 - threshold) / 2.0).max(f32::EPSILON);
+
+        let mut highlights = RgbaImage::new(width, height);
+        for x in 0..width {
+            cancel.check()?;
+            for y in 0..height {
+                let pixel = result.get_pixel(x, y);
+                let luminance = Self::sort_key(pixel, SortKey::Luminance);
+                let weight = ((luminance - threshold) / knee).clamp(0.0, 1.0);
+                let glow = [0, 1, 2].map(|channel| (pixel[channel] as f32 * weight) as u8);
+                highlights.put_pixel(x, y, Rgba([glow[0], glow[1], glow[2], 255]));
+            }
+        }
+        let highlights = DynamicImage::ImageRgba8(highlights);
+
+        let mut glow = vec![[0.0f32; 3]; (width * height) as usize];
+        for radius in radii {
+            cancel.check()?;
+            let blurred = highlights.blur(radius.max(0.1)).to_rgba8();
+            for (total, pixel) in glow.iter_mut().zip(blurred.pixels()) {
+                for channel in 0..3 {
+                    total[channel] += pixel[channel] as f32 / 255.0 / radii.len() as f32;
+                }
+            }
+        }
+
+        for x in 0..width {
+            cancel.check()?;
+            for y in 0..height {
+                // The glow covers the image as far as it shines, so modes
+                // other than screen and add leave unlit areas alone too.
+                let glow = glow[(y * width + x) as usize];
+                let strength = glow[0].max(glow[1]).max(glow[2]);
+                if strength <= 0.0 {
+                    continue;
+                }
+                let top = std::array::from_fn(|channel| {
+                    let tint = tint.map_or(1.0, |tint| tint[channel] as f32 / 255.0);
+                    (glow[channel] / strength * tint).clamp(0.0, 1.0)
+                });
+                let amount = (strength * intensity).clamp(0.0, 1.0);
+                Self::composite(result.get_pixel_mut(x, y), top, blend_mode, amount);
+            }
+        }
+
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    fn resolve_palette(img: &RgbaImage, palette: &Palette) -> Result<Vec<Rgba<u8>>> {
+        let colors = match palette {
+            Palette::Colors(colors) => colors
+                .iter()
+                .map(|color| Self::parse_color(color))
+                .collect::<Result<Vec<_>>>()?,
+            Palette::Quantized(count) => Self::median_cut(img, (*count).clamp(2, 256) as usize),
+        };
+        if colors.is_empty() {
+            return Err(anyhow::anyhow!("Palette must have at least one color"));
+        }
+        Ok(colors)
+    }
+
+    /// Picks up to `count` colors representing the image by repeatedly
+    /// splitting the box of colors with the widest channel range at its median.
+    fn median_cut(img: &RgbaImage, count: usize) -> Vec<Rgba<u8>> {
+        // A sample of at most about 64k pixels is plenty to pick colors from.
+        let step = (img.pixels().len() / 65_536).max(1);
+        let mut boxes = vec![
+            img.pixels()
+                .step_by(step)
+                .map(|p| [p[0], p[1], p[2]])
+                .collect::<Vec<_>>(),
+        ];
+
+        while boxes.len() < count {
+            let widest = boxes
+                .iter()
+                .enumerate()
+                .filter(|(_, colors)| colors.len() > 1)
+                .map(|(index, colors)| {
+                    let (channel, range) = (0..3)
+                        .map(|channel| {
+                            let values = colors.iter().map(|color| color[channel]);
+                            let range = values.clone().max().unwrap() - values.min().unwrap();
+                            (channel, range)
+                        })
+                        .max_by_key(|&(_, range)| range)
+                        .unwrap();
+                    (index, channel, range)
+                })
+                .max_by_key(|&(_, _, range)| range);
+            let Some((index, channel, range)) = widest else {
+                break;
+            };
+            if range == 0 {
+                break;
+            }
+            let colors = &mut boxes[index];
+            colors.sort_unstable_by_key(|color| color[channel]);
+            let upper = colors.split_off(colors.len() / 2);
+            boxes.push(upper);
+        }
+
+        boxes
+            .iter()
+            .filter(|colors| !colors.is_empty())
+            .map(|colors| {
+                let mut sum = [0u64; 3];
+                for color in colors {
+                    for channel in 0..3 {
+                        sum[channel] += color[channel] as u64;
+                    }
+                }
+                let average = sum.map(|sum| (sum / colors.len() as u64) as u8);
+                Rgba([average[0], average[1], average[2], 255])
+            })
+            .collect()
+    }
+
+    fn nearest_color(colors: &[Rgba<u8>], target: [f32; 3]) -> Rgba<u8> {
+        *colors
+            .iter()
+            .min_by(|a, b| {
+                let distance = |color: &Rgba<u8>| {
+                    (0..3)
+                        .map(|channel| (color[channel] as f32 - target[channel]).powi(2))
+                        .sum::<f32>()
+                };
+                distance(a).total_cmp(&distance(b))
+            })
+            .unwrap()
+    }
+
+    fn vignette(
+        img: &DynamicImage,
+        intensity: f32,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        let mut result = img.to_rgba8();
+        let (width, height) = result.dimensions();
+        let center_x = width as f32 / 2.0;
+        let center_y = height as f32 / 2.0;
+        let max_dist = (center_x * center_x + center_y * center_y).sqrt();
+
+        for x in 0..width {
+            cancel.check()?;
+            for y in 0..height {
+                let dx = center_x - x as f32;
+                let dy = center_y - y as f32;
+                let dist = (dx * dx + dy * dy).sqrt();
+                let factor = 1.0 - (dist / max_dist) * intensity;
+
+                let mut pixel = *result.get_pixel(x, y);
+                pixel[0] = (pixel[0] as f32 * factor) as u8;
+                pixel[1] = (pixel[1] as f32 * factor) as u8;
+                pixel[2] = (pixel[2] as f32 * factor) as u8;
+
+                result.put_pixel(x, y, pixel);
+            }
+        }
+
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    fn sepia(img: &DynamicImage, cancel: &CancellationToken) -> Result<DynamicImage> {
+        let mut result = img.to_rgba8();
+        let (width, height) = result.dimensions();
+
+        for x in 0..width {
+            cancel.check()?;
+            for y in 0..height {
+                let mut pixel = *result.get_pixel(x, y);
+                let r = pixel[0] as f32;
+                let g = pixel[1] as f32;
+                let b = pixel[2] as f32;
+
+                let tr = (0.393 * r + 0.769 * g + 0.189 * b).min(255.0);
+                let tg = (0.349 * r + 0.686 * g + 0.168 * b).min(255.0);
+                let tb = (0.272 * r + 0.534 * g + 0.131 * b).min(255.0);
+
+                pixel[0] = tr as u8;
+                pixel[1] = tg as u8;
+                pixel[2] = tb as u8;
+
+                result.put_pixel(x, y, pixel);
+            }
+        }
+
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    fn brightness(img: &DynamicImage, value: f32) -> DynamicImage {
+        let mut result = img.to_rgba8();
+        colorops::brighten_in_place(&mut result, value as i32);
+        DynamicImage::ImageRgba8(result)
+    }
+
+    fn contrast(img: &DynamicImage, value: f32) -> DynamicImage {
+        let mut result = img.to_rgba8();
+        colorops::contrast_in_place(&mut result, value);
+        DynamicImage::ImageRgba8(result)
+    }
+
+    fn saturation(
+        img: &DynamicImage,
+        value: f32,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        // Упрощенная реализация насыщенности
+        let mut result = img.to_rgba8();
+        let (width, height) = result.dimensions();
+
+        for x in 0..width {
+            cancel.check()?;
+            for y in 0..height {
+                let mut pixel = *result.get_pixel(x, y);
+                let r = pixel[0] as f32;
+                let g = pixel[1] as f32;
+                let b = pixel[2] as f32;
+
+                let gray = 0.299 * r + 0.587 * g + 0.114 * b;
+
+                pixel[0] = (gray + (r - gray) * value).clamp(0.0, 255.0) as u8;
+                pixel[1] = (gray + (g - gray) * value).clamp(0.0, 255.0) as u8;
+                pixel[2] = (gray + (b - gray) * value).clamp(0.0, 255.0) as u8;
+
+                result.put_pixel(x, y, pixel);
+            }
+        }
+
+        Ok(DynamicImage::ImageRgba8(result))
+    }
+
+    fn hue_rotate(img: &DynamicImage, degrees: f32) -> DynamicImage {
+        let mut result = img.to_rgba8();
+        colorops::huerotate_in_place(&mut result, degrees as i32);
+        DynamicImage::ImageRgba8(result)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn draw_text(
+        img: &DynamicImage,
+        content: &str,
+        font_data: &[u8],
+        size: f32,
+        color: &str,
+        x: i32,
+        y: i32,
+        stroke: Option<&Stroke>,
+        shadow: Option<&Shadow>,
+        cancel: &CancellationToken,
+    ) -> Result<DynamicImage> {
+        let mut result = img.to_rgba8();
+
+        let font = ab_glyph::FontRef::try_from_slice(font_data)?;
+
+        let scale = size;
+        let color = Self::parse_color(color)?;
+
+        if let Some(shadow) = shadow {
+            let shadow_color = Self::parse_color(&shadow.color)?;
+            drawing::draw_text_mut(
+                &mut result,
+                shadow_color,
+                x + shadow.offset_x,
+                y + shadow.offset_y,
+                scale,
                 &font,
                 content,
             );
@@ -388,6 +2047,7 @@ impl ImageProcessor {
             let stroke_width = stroke.width as i32;
 
             for dx in -stroke_width..=stroke_width {
+                cancel.check()?;
                 for dy in -stroke_width..=stroke_width {
                     if dx != 0 || dy != 0 {
                         drawing::draw_text_mut(
@@ -411,7 +2071,8 @@ impl ImageProcessor {
 
     fn parse_color(hex: &str) -> Result<Rgba<u8>> {
         let hex = hex.trim_start_matches('#');
-        if hex.len() < 6 {
+        // Checked first so the byte slicing below stays on char boundaries.
+        if !hex.is_ascii() || hex.len() < 6 {
             return Err(anyhow::anyhow!("Invalid color format"));
         }
 
@@ -427,3 +2088,557 @@ impl ImageProcessor {
         Ok(Rgba([r, g, b, a]))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// FNV-1a over the pixels, to pin an image in a few characters.
+    fn fingerprint(img: &DynamicImage) -> u64 {
+        img.to_rgba8()
+            .as_raw()
+            .iter()
+            .fold(0xcbf29ce484222325, |hash, &byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+            })
+    }
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(16, 16, |x, y| {
+            Rgba([(x * 16) as u8, (y * 16) as u8, 128, 255])
+        }))
+    }
+
+    fn filter(filter: serde_json::Value) -> DynamicImage {
+        apply(&gradient(), filter)
+    }
+
+    fn apply(img: &DynamicImage, filter: serde_json::Value) -> DynamicImage {
+        try_apply(img, filter).unwrap()
+    }
+
+    fn try_apply(img: &DynamicImage, filter: serde_json::Value) -> Result<DynamicImage> {
+        let filter = serde_json::from_value(filter)?;
+        ImageProcessor::apply_filter_operation(img, &filter, 42, &RenderContext::default())
+    }
+
+    /// A single row of grey pixels.
+    fn row(values: &[u8]) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(values.len() as u32, 1, |x, _| {
+            let value = values[x as usize];
+            Rgba([value, value, value, 255])
+        }))
+    }
+
+    /// The red channel of the first row.
+    fn reds(img: &DynamicImage) -> Vec<u8> {
+        let img = img.to_rgba8();
+        (0..img.width()).map(|x| img.get_pixel(x, 0)[0]).collect()
+    }
+
+    const EDGE_MODES: [&str; 4] = ["clamp", "wrap", "mirror", "black"];
+
+    #[test]
+    fn parses_hex_colors() {
+        let parse = ImageProcessor::parse_color;
+        assert_eq!(parse("#ff8000").unwrap(), Rgba([255, 128, 0, 255]));
+        assert_eq!(parse("0000ff80").unwrap(), Rgba([0, 0, 255, 128]));
+        assert!(parse("#fff").is_err());
+        assert!(parse("#zz0000").is_err());
+        // Multi-byte characters must be rejected, not sliced through.
+        assert!(parse("#0ä0000").is_err());
+        assert!(parse("#00€00").is_err());
+    }
+
+    #[test]
+    fn curves_pass_through_their_points() {
+        let identity = ImageProcessor::curve_table(&[]).unwrap();
+        assert!(identity.iter().enumerate().all(|(x, &y)| x == y as usize));
+        assert_eq!(
+            ImageProcessor::curve_table(&[[1.0, 1.0], [0.0, 0.0]]).unwrap(),
+            identity
+        );
+
+        let table = ImageProcessor::curve_table(&[[0.0, 0.0], [0.2, 0.6], [1.0, 1.0]]).unwrap();
+        assert_eq!((table[0], table[51], table[255]), (0, 153, 255));
+    }
+
+    #[test]
+    fn curves_do_not_overshoot() {
+        // A steep S-curve stays monotone and within its points.
+        let table =
+            ImageProcessor::curve_table(&[[0.0, 0.0], [0.4, 0.05], [0.6, 0.95], [1.0, 1.0]])
+                .unwrap();
+        assert!(table.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(table[102..=153].iter().all(|&y| (13..=242).contains(&y)));
+
+        // A plateau between two equal points stays flat.
+        let table =
+            ImageProcessor::curve_table(&[[0.0, 0.2], [0.2, 0.5], [0.8, 0.5], [1.0, 0.9]]).unwrap();
+        assert!(table[51..=204].iter().all(|&y| y == 128));
+    }
+
+    #[test]
+    fn curves_hold_flat_outside_their_points() {
+        let table = ImageProcessor::curve_table(&[[0.2, 0.3], [0.8, 0.7]]).unwrap();
+        assert!(table[..=51].iter().all(|&y| y == table[51]));
+        assert!(table[204..].iter().all(|&y| y == table[204]));
+    }
+
+    #[test]
+    fn rejects_invalid_curves() {
+        assert!(ImageProcessor::curve_table(&[[0.5, 0.5]]).is_err());
+        assert!(ImageProcessor::curve_table(&[[0.0, 0.0], [0.5, 0.2], [0.5, 0.8]]).is_err());
+    }
+
+    #[test]
+    fn overlays_blend_with_their_mode() {
+        let overlay = |color: u8, alpha: u8, opacity, mode| {
+            let mut base = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, Rgba([128; 4])));
+            let top = DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+                1,
+                1,
+                Rgba([color, color, color, alpha]),
+            ));
+            let cancel = CancellationToken::new();
+            ImageProcessor::overlay_blended(&mut base, &top, 1, 1, opacity, mode, &cancel).unwrap();
+            base.to_rgba8()
+        };
+
+        let multiplied = overlay(128, 255, 1.0, BlendMode::Multiply);
+        assert_eq!(multiplied.get_pixel(0, 0)[0], 128);
+        assert_eq!(multiplied.get_pixel(1, 1)[0], 64);
+        // The overlay's alpha and the opacity both scale the effect.
+        assert_eq!(
+            overlay(128, 128, 1.0, BlendMode::Multiply).get_pixel(1, 1)[0],
+            96
+        );
+        assert_eq!(
+            overlay(128, 255, 0.5, BlendMode::Multiply).get_pixel(1, 1)[0],
+            96
+        );
+        assert_eq!(
+            overlay(255, 255, 1.0, BlendMode::Screen).get_pixel(1, 1)[0],
+            255
+        );
+        assert_eq!(
+            overlay(0, 255, 0.5, BlendMode::Normal).get_pixel(1, 1)[0],
+            64
+        );
+    }
+
+    #[test]
+    fn emboss_renders_a_grey_relief() {
+        let embossed = filter(serde_json::json!({ "name": "emboss", "amount": 1.0 })).to_rgba8();
+        assert!(
+            embossed
+                .pixels()
+                .all(|p| p[0] == p[1] && p[1] == p[2] && p[3] == 255)
+        );
+        // The gradient rises to the bottom right, so it lights up.
+        assert!(embossed.get_pixel(8, 8)[0] > 128);
+
+        let flat = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([200, 40, 90, 255])));
+        let filter = serde_json::from_value(serde_json::json!({ "name": "emboss" })).unwrap();
+        let embossed =
+            ImageProcessor::apply_filter_operation(&flat, &filter, 0, &RenderContext::default())
+                .unwrap();
+        assert!(
+            embossed
+                .to_rgba8()
+                .pixels()
+                .all(|p| p.0 == [128, 128, 128, 255])
+        );
+    }
+
+    #[test]
+    fn sharpening_overshoots_edges_and_keeps_flat_areas() {
+        let step = row(&[50, 50, 50, 200, 200, 200]);
+        let sharpened = apply(
+            &step,
+            serde_json::json!({ "name": "sharpen", "amount": 1.0 }),
+        );
+        assert_eq!(reds(&sharpened), [50, 50, 0, 255, 200, 200]);
+
+        let unsharp = |threshold: f32| {
+            reds(&apply(
+                &step,
+                serde_json::json!({
+                    "name": "unsharp_mask", "radius": 1.0, "amount": 1.0, "threshold": threshold,
+                }),
+            ))
+        };
+        let sharpened = unsharp(0.0);
+        assert!(sharpened[2] < 50 && sharpened[3] > 200, "{:?}", sharpened);
+        // No difference reaches a full threshold.
+        assert_eq!(unsharp(1.0), reds(&step));
+    }
+
+    #[test]
+    fn edge_detection_finds_only_edges() {
+        let step = DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, |x, _| {
+            let value = if x < 4 { 0 } else { 255 };
+            Rgba([value, value, value, 255])
+        }));
+        for method in ["sobel", "canny"] {
+            let edges = apply(
+                &step,
+                serde_json::json!({ "name": "edge_detect", "method": method }),
+            )
+            .to_rgba8();
+            for (x, y, pixel) in edges.enumerate_pixels() {
+                assert!(pixel[0] == pixel[1] && pixel[1] == pixel[2] && pixel[3] == 255);
+                if !(3..=4).contains(&x) {
+                    assert_eq!(pixel[0], 0, "{} at {}, {}", method, x, y);
+                }
+            }
+            assert!(edges.pixels().any(|p| p[0] == 255), "{}", method);
+        }
+        let inverted = serde_json::json!({
+            "name": "edge_detect", "method": "canny", "low": 0.5, "high": 0.2,
+        });
+        assert!(try_apply(&step, inverted).is_err());
+    }
+
+    #[test]
+    fn convolve_applies_odd_kernels() {
+        let identity = filter(serde_json::json!({
+            "name": "convolve", "kernel": [[0, 0, 0], [0, 1, 0], [0, 0, 0]],
+        }));
+        assert_eq!(fingerprint(&identity), fingerprint(&gradient()));
+
+        let averaged = apply(
+            &row(&[30, 60, 90, 120]),
+            serde_json::json!({ "name": "convolve", "kernel": [[1, 1, 1]] }),
+        );
+        assert_eq!(reds(&averaged), [40, 60, 90, 110]);
+
+        let biased =
+            filter(serde_json::json!({ "name": "convolve", "kernel": [[0]], "bias": 0.5 }));
+        assert!(
+            biased
+                .to_rgba8()
+                .pixels()
+                .all(|p| p.0 == [128, 128, 128, 255])
+        );
+    }
+
+    #[test]
+    fn convolve_rejects_kernels_without_a_centre() {
+        let kernels = [
+            serde_json::json!([]),
+            serde_json::json!([[]]),
+            serde_json::json!([[1, 1]]),
+            serde_json::json!([[1], [1]]),
+            serde_json::json!([[1, 1, 1], [1], [1, 1, 1]]),
+        ];
+        for kernel in kernels {
+            let convolve = serde_json::json!({ "name": "convolve", "kernel": kernel });
+            assert!(try_apply(&gradient(), convolve).is_err(), "{}", kernel);
+        }
+    }
+
+    #[test]
+    fn bloom_leaves_areas_without_highlights_alone() {
+        // Dark grey with a white highlight in the top left corner.
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(16, 16, |x, y| {
+            let value = if x < 2 && y < 2 { 255 } else { 40 };
+            Rgba([value, value, value, 255])
+        }));
+        let modes = [
+            "normal",
+            "multiply",
+            "screen",
+            "overlay",
+            "soft_light",
+            "add",
+        ];
+        for mode in modes {
+            let bloomed = apply(
+                &img,
+                serde_json::json!({
+                    "name": "bloom", "radii": [1.0], "blend_mode": mode, "intensity": 2.0,
+                }),
+            )
+            .to_rgba8();
+            for x in 10..16 {
+                for y in 10..16 {
+                    assert_eq!(bloomed.get_pixel(x, y).0, [40, 40, 40, 255], "{}", mode);
+                }
+            }
+            if matches!(mode, "normal" | "screen" | "add") {
+                assert!(bloomed.get_pixel(2, 2)[0] > 40, "{} didn't glow", mode);
+            }
+        }
+    }
+
+    #[test]
+    fn chromatic_aberration_samples_outside_by_edge_mode() {
+        let expected = [
+            [10, 10, 10, 20],
+            [30, 40, 10, 20],
+            [20, 10, 10, 20],
+            [0, 0, 10, 20],
+        ];
+        for (edge, expected) in EDGE_MODES.into_iter().zip(expected) {
+            let shifted = apply(
+                &row(&[10, 20, 30, 40]),
+                serde_json::json!({
+                    "name": "chromatic_aberration", "red": [2.0, 0.0], "edge": edge,
+                }),
+            );
+            assert_eq!(reds(&shifted), expected, "{}", edge);
+            // Green wasn't moved.
+            assert_eq!(shifted.to_rgba8().get_pixel(0, 0)[1], 10);
+        }
+    }
+
+    #[test]
+    fn huge_offsets_do_not_overflow() {
+        for edge in EDGE_MODES {
+            for offset in [1e30, -1e30, f32::MAX, f32::MIN] {
+                apply(
+                    &row(&[10, 20, 30, 40]),
+                    serde_json::json!({
+                        "name": "chromatic_aberration",
+                        "red": [offset, -offset],
+                        "radial": offset,
+                        "edge": edge,
+                    }),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn motion_blur_samples_outside_by_edge_mode() {
+        let expected = [[16, 34], [26, 24], [18, 32], [12, 18]];
+        for (edge, expected) in EDGE_MODES.into_iter().zip(expected) {
+            let blurred = apply(
+                &row(&[10, 20, 30, 40]),
+                serde_json::json!({
+                    "name": "motion_blur", "angle": 0.0, "length": 4.0, "edge": edge,
+                }),
+            );
+            let reds = reds(&blurred);
+            assert_eq!([reds[0], reds[3]], expected, "{}", edge);
+
+            for length in [1e30, -1e30, f32::MAX, f32::MIN] {
+                apply(
+                    &row(&[10, 20, 30, 40]),
+                    serde_json::json!({
+                        "name": "motion_blur", "angle": 30.0, "length": length, "edge": edge,
+                    }),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_sort_sorts_runs_within_threshold() {
+        let img = row(&[250, 200, 100, 150, 120, 10, 180, 90]);
+        let sorted = apply(&img, serde_json::json!({"name": "pixel_sort"}));
+        // 250 and 10 are outside the default threshold, so they stay put and
+        // split the row into two runs.
+        assert_eq!(reds(&sorted), [250, 100, 120, 150, 200, 10, 90, 180]);
+
+        let sorted = apply(
+            &img,
+            serde_json::json!({"name": "pixel_sort", "reverse": true}),
+        );
+        assert_eq!(reds(&sorted), [250, 200, 150, 120, 100, 10, 180, 90]);
+    }
+
+    #[test]
+    fn pixel_sort_orders_by_key() {
+        let pixels = [[200, 200, 200], [255, 0, 0], [100, 50, 50], [40, 40, 40]];
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 1, |x, _| {
+            let [r, g, b] = pixels[x as usize];
+            Rgba([r, g, b, 255])
+        }));
+        let sorted = apply(
+            &img,
+            serde_json::json!({"name": "pixel_sort", "key": "saturation", "threshold": [0.0, 1.0]}),
+        )
+        .to_rgba8();
+        let sorted: Vec<[u8; 3]> = sorted.pixels().map(|p| [p[0], p[1], p[2]]).collect();
+        assert_eq!(sorted, [pixels[0], pixels[3], pixels[2], pixels[1]]);
+    }
+
+    #[test]
+    fn grain_options_are_seeded() {
+        let grey =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(16, 16, Rgba([128, 128, 128, 255])));
+        let grain = |seed: u64, monochrome: bool| {
+            apply(
+                &grey,
+                serde_json::json!({
+                    "name": "grain", "intensity": 0.5, "size": 3.0,
+                    "monochrome": monochrome, "seed": seed,
+                }),
+            )
+            .to_rgba8()
+        };
+
+        for monochrome in [true, false] {
+            assert_eq!(grain(1, monochrome), grain(1, monochrome));
+            assert_ne!(grain(1, monochrome), grain(2, monochrome));
+        }
+        let grey_channels = |img: &RgbaImage| img.pixels().all(|p| p[0] == p[1] && p[1] == p[2]);
+        assert!(grey_channels(&grain(1, true)));
+        assert!(!grey_channels(&grain(1, false)));
+    }
+
+    #[test]
+    fn halftone_prints_only_inks() {
+        let mut fingerprints = Vec::new();
+        for mode in ["mono", "cmyk"] {
+            for pattern in ["dot", "line", "cross"] {
+                let screen = serde_json::json!({
+                    "name": "halftone", "pattern": pattern, "cell_size": 4.0, "mode": mode,
+                });
+                let printed = filter(screen.clone());
+                assert_eq!(fingerprint(&printed), fingerprint(&filter(screen)));
+                fingerprints.push(fingerprint(&printed));
+
+                let printed = printed.to_rgba8();
+                assert!(
+                    printed
+                        .pixels()
+                        .flat_map(|p| &p.0[..3])
+                        .all(|&v| v == 0 || v == 255)
+                );
+                let colored = printed.pixels().any(|p| p[0] != p[1] || p[1] != p[2]);
+                assert_eq!(colored, mode == "cmyk", "{} {}", mode, pattern);
+            }
+        }
+        // Screens aren't random, but renders must be just as reproducible.
+        assert_eq!(
+            fingerprints,
+            [
+                0x9d8b2b1beb20b415,
+                0x2aa51854b9542834,
+                0x3d26bd4cf2e902c5,
+                0xb8a4e235e42b9fdd,
+                0xc7b7a7ac26fad3d7,
+                0x773660b7a4f9271d
+            ]
+        );
+    }
+
+    #[test]
+    fn bayer_thresholds_are_a_permutation() {
+        assert_eq!(ImageProcessor::bayer_matrix(2), [0, 2, 3, 1]);
+        for size in [2, 4, 8] {
+            let mut matrix = ImageProcessor::bayer_matrix(size);
+            matrix.sort_unstable();
+            assert_eq!(matrix, (0..size * size).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn dithering_uses_only_palette_colors() {
+        let colors = |img: &DynamicImage| {
+            let mut colors: Vec<Rgba<u8>> = img.to_rgba8().pixels().copied().collect();
+            colors.sort_unstable_by_key(|p| p.0);
+            colors.dedup();
+            colors
+        };
+        let palette = ["#000000", "#ff0000", "#0000ff", "#ffffff"];
+        let named = palette.map(|color| ImageProcessor::parse_color(color).unwrap());
+        let quantized = ImageProcessor::median_cut(&gradient().to_rgba8(), 4);
+        assert!(!quantized.is_empty() && quantized.len() <= 4);
+
+        let mut dithers = vec![serde_json::json!({"name": "ordered_dither"})];
+        for kernel in ["floyd_steinberg", "atkinson", "jarvis"] {
+            dithers.push(serde_json::json!({"name": "error_diffusion", "kernel": kernel}));
+        }
+        for dither in dithers {
+            let mut with_palette = dither.clone();
+            with_palette["palette"] = serde_json::json!(palette);
+            for color in colors(&filter(with_palette)) {
+                assert!(named.contains(&color), "{} made {:?}", dither, color);
+            }
+
+            let mut with_quantized = dither.clone();
+            with_quantized["palette"] = serde_json::json!(4);
+            for color in colors(&filter(with_quantized)) {
+                assert!(quantized.contains(&color), "{} made {:?}", dither, color);
+            }
+
+            let black_and_white = [Rgba([0, 0, 0, 255]), Rgba([255, 255, 255, 255])];
+            assert_eq!(
+                colors(&filter(dither.clone())),
+                black_and_white,
+                "{}",
+                dither
+            );
+        }
+    }
+
+    #[test]
+    fn error_diffusion_is_deterministic() {
+        for kernel in ["floyd_steinberg", "atkinson", "jarvis"] {
+            let diffuse =
+                || filter(serde_json::json!({"name": "error_diffusion", "kernel": kernel}));
+            assert_eq!(
+                fingerprint(&diffuse()),
+                fingerprint(&diffuse()),
+                "{}",
+                kernel
+            );
+        }
+    }
+
+    #[test]
+    fn levels_map_black_and_white_points_and_gamma() {
+        let img = row(&[0, 51, 64, 102, 153, 204, 255]);
+        let levels = |options: serde_json::Value| {
+            let mut levels = serde_json::json!({"name": "levels"});
+            levels
+                .as_object_mut()
+                .unwrap()
+                .extend(options.as_object().unwrap().clone());
+            try_apply(&img, levels).map(|img| reds(&img))
+        };
+
+        let points = levels(serde_json::json!({"input_black": 0.2, "input_white": 0.8})).unwrap();
+        assert_eq!(points, [0, 0, 22, 85, 170, 255, 255]);
+        let gamma = levels(serde_json::json!({"gamma": 2.0})).unwrap();
+        assert_eq!([gamma[0], gamma[2], gamma[6]], [0, 128, 255]);
+        let output = levels(serde_json::json!({"output_black": 0.2, "output_white": 0.6})).unwrap();
+        assert_eq!([output[0], output[6]], [51, 153]);
+
+        assert!(levels(serde_json::json!({"input_black": 0.5, "input_white": 0.5})).is_err());
+        assert!(levels(serde_json::json!({"gamma": 0.0})).is_err());
+    }
+
+    #[test]
+    fn auto_levels_stretch_to_the_full_range() {
+        let values: Vec<u8> = (100..=150).collect();
+        let stretched = reds(&apply(
+            &row(&values),
+            serde_json::json!({"name": "levels", "auto": true, "auto_clip": 0.0}),
+        ));
+        assert_eq!(stretched.iter().min(), Some(&0));
+        assert_eq!(stretched.iter().max(), Some(&255));
+        assert!(stretched.is_sorted());
+    }
+
+    /// Random filters must render the same for a seed on every platform and
+    /// dependency version, or saved seeds stop reproducing their images.
+    #[test]
+    fn random_filters_are_pinned_to_their_seed() {
+        let grain = filter(serde_json::json!({ "name": "grain", "intensity": 0.5 }));
+        let blocks = filter(serde_json::json!({
+            "name": "block_corruption", "block_size": 4, "amount": 0.5, "seed": 7,
+        }));
+        let slices = filter(serde_json::json!({
+            "name": "slice_displacement", "slices": 4, "max_offset": 5,
+        }));
+        assert_eq!(fingerprint(&grain), 0x4a032c42fe89d6b0);
+        assert_eq!(fingerprint(&blocks), 0xf459e1aabfee2fe5);
+        assert_eq!(fingerprint(&slices), 0x926901d0a213cc25);
+        assert_ne!(fingerprint(&grain), fingerprint(&gradient()));
+    }
+}
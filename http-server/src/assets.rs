@@ -0,0 +1,372 @@
+use core::assets::{AssetKind, AssetResolver, LocalAssets};
+use core::lut::Lut;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, anyhow, bail};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Prefix for referencing a library asset from a config, e.g. `asset://3f2a...`.
+pub const ASSET_SCHEME: &str = "asset://";
+
+/// Metadata stored next to every uploaded asset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Asset {
+    pub id: String,
+    /// Original file name, if the client sent one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub size: u64,
+    /// Upload time in seconds since the Unix epoch.
+    pub created: u64,
+    /// Family names from the font's `name` table.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub families: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// Image format of an overlay, e.g. `png`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// Entries per axis of a LUT.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lut_size: Option<usize>,
+}
+
+/// Fonts, overlay textures and LUTs uploaded through the API, stored under a
+/// managed directory as `<root>/<kind>/<id>` with `<id>.json` metadata.
+///
+/// The methods block on the file system and on parsing uploads, so async
+/// code calls them through `smol::unblock`.
+pub struct AssetLibrary {
+    root: PathBuf,
+}
+
+impl AssetLibrary {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Validates and stores a new asset, returning its metadata.
+    pub fn upload(&self, kind: AssetKind, name: Option<String>, data: &[u8]) -> Result<Asset> {
+        let mut asset = Asset {
+            id: format!("{:016x}", rand::thread_rng().r#gen::<u64>()),
+            name,
+            size: data.len() as u64,
+            created: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            families: None,
+            width: None,
+            height: None,
+            format: None,
+            lut_size: None,
+        };
+
+        match kind {
+            AssetKind::Font => asset.families = Some(font_families(data)?),
+            AssetKind::Overlay => {
+                let reader = image::ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+                let format = reader
+                    .format()
+                    .ok_or_else(|| anyhow!("Unknown image format"))?;
+                let (width, height) = reader.into_dimensions()?;
+                asset.format = format.extensions_str().first().map(|e| e.to_string());
+                asset.width = Some(width);
+                asset.height = Some(height);
+            }
+            AssetKind::Lut => {
+                asset.lut_size = Some(Lut::parse(data)?.size());
+            }
+        }
+
+        let dir = self.dir(kind);
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(&asset.id), data)?;
+        fs::write(
+            dir.join(format!("{}.json", asset.id)),
+            serde_json::to_vec_pretty(&asset)?,
+        )?;
+
+        Ok(asset)
+    }
+
+    /// Lists all assets of a kind, oldest first.
+    pub fn list(&self, kind: AssetKind) -> Result<Vec<Asset>> {
+        let dir = self.dir(kind);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut assets = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                assets.push(serde_json::from_slice::<Asset>(&fs::read(path)?)?);
+            }
+        }
+        assets.sort_by(|a, b| a.created.cmp(&b.created).then(a.id.cmp(&b.id)));
+        Ok(assets)
+    }
+
+    /// Returns the metadata of an asset, or `None` if it doesn't exist.
+    pub fn metadata(&self, kind: AssetKind, id: &str) -> Result<Option<Asset>> {
+        let Some(path) = self.file(kind, id) else {
+            return Ok(None);
+        };
+        let path = path.with_extension("json");
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+    }
+
+    /// Returns the contents of an asset, or `None` if it doesn't exist.
+    pub fn read(&self, kind: AssetKind, id: &str) -> Result<Option<Vec<u8>>> {
+        match self.file(kind, id) {
+            Some(path) if path.exists() => Ok(Some(fs::read(path)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Deletes an asset. Returns `false` if it didn't exist.
+    pub fn delete(&self, kind: AssetKind, id: &str) -> Result<bool> {
+        let Some(path) = self.file(kind, id) else {
+            return Ok(false);
+        };
+        if !path.exists() {
+            return Ok(false);
+        }
+        fs::remove_file(path.with_extension("json"))?;
+        fs::remove_file(path)?;
+        Ok(true)
+    }
+
+    fn dir(&self, kind: AssetKind) -> PathBuf {
+        self.root.join(kind_name(kind))
+    }
+
+    /// Path of the asset file. IDs are generated hex strings, anything else
+    /// is rejected so it can't escape the library directory.
+    fn file(&self, kind: AssetKind, id: &str) -> Option<PathBuf> {
+        let valid = !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit());
+        valid.then(|| self.dir(kind).join(id))
+    }
+}
+
+impl AssetResolver for AssetLibrary {
+    fn resolve(&self, kind: AssetKind, reference: &Path) -> Result<PathBuf> {
+        let Some(id) = reference
+            .to_str()
+            .and_then(|r| r.strip_prefix(ASSET_SCHEME))
+        else {
+            return LocalAssets.resolve(kind, reference);
+        };
+
+        match self.file(kind, id) {
+            Some(path) if path.exists() => Ok(path),
+            _ => bail!("Unknown {} asset {}", kind_name(kind), id),
+        }
+    }
+}
+
+/// Parses the plural kind used in URLs, e.g. `fonts`.
+pub fn parse_kind(kind: &str) -> Option<AssetKind> {
+    match kind {
+        "fonts" => Some(AssetKind::Font),
+        "overlays" => Some(AssetKind::Overlay),
+        "luts" => Some(AssetKind::Lut),
+        _ => None,
+    }
+}
+
+fn kind_name(kind: AssetKind) -> &'static str {
+    match kind {
+        AssetKind::Font => "fonts",
+        AssetKind::Overlay => "overlays",
+        AssetKind::Lut => "luts",
+    }
+}
+
+/// Checks the font loads with ab_glyph and reads its family names.
+fn font_families(data: &[u8]) -> Result<Vec<String>> {
+    ab_glyph::FontRef::try_from_slice(data)?;
+
+    let face = ttf_parser::Face::parse(data, 0)?;
+    let mut families = Vec::new();
+    for name in face.names() {
+        if name.name_id == ttf_parser::name_id::FAMILY
+            && let Some(family) = name.to_string()
+            && !families.contains(&family)
+        {
+            families.push(family);
+        }
+    }
+    Ok(families)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A library in its own temporary directory, removed when dropped.
+    struct TempLibrary(AssetLibrary);
+
+    impl TempLibrary {
+        fn new() -> Self {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let root = std::env::temp_dir().join(format!(
+                "ccimg-assets-{}-{}",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            ));
+            Self(AssetLibrary::new(root))
+        }
+    }
+
+    impl Drop for TempLibrary {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0.root);
+        }
+    }
+
+    const CUBE: &str = "LUT_3D_SIZE 2\n0 0 0\n1 0 0\n0 1 0\n1 1 0\n0 0 1\n1 0 1\n0 1 1\n1 1 1\n";
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        image::DynamicImage::new_rgba8(width, height)
+            .write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn only_hex_ids_map_to_files() {
+        let library = AssetLibrary::new("/library");
+        assert_eq!(
+            library.file(AssetKind::Lut, "00ff3a"),
+            Some(PathBuf::from("/library/luts/00ff3a"))
+        );
+        for id in [
+            "",
+            "..",
+            "../00ff",
+            "00/ff",
+            "/etc/passwd",
+            "00ff.json",
+            "0g",
+            "00ff\0",
+        ] {
+            assert_eq!(library.file(AssetKind::Lut, id), None, "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn uploads_lists_reads_and_deletes_assets() {
+        let library = TempLibrary::new();
+        let library = &library.0;
+
+        let lut = library
+            .upload(AssetKind::Lut, Some("warm.cube".into()), CUBE.as_bytes())
+            .unwrap();
+        assert_eq!(lut.lut_size, Some(2));
+        let overlay = library
+            .upload(AssetKind::Overlay, None, &png(3, 2))
+            .unwrap();
+        assert_eq!((overlay.width, overlay.height), (Some(3), Some(2)));
+        assert_eq!(overlay.format.as_deref(), Some("png"));
+
+        let listed = library.list(AssetKind::Lut).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, lut.id);
+        assert_eq!(listed[0].name.as_deref(), Some("warm.cube"));
+        assert!(library.list(AssetKind::Font).unwrap().is_empty());
+
+        assert_eq!(
+            library.read(AssetKind::Lut, &lut.id).unwrap().unwrap(),
+            CUBE.as_bytes()
+        );
+        assert_eq!(
+            library
+                .metadata(AssetKind::Lut, &lut.id)
+                .unwrap()
+                .unwrap()
+                .size,
+            CUBE.len() as u64
+        );
+        // IDs are per kind.
+        assert!(library.read(AssetKind::Overlay, &lut.id).unwrap().is_none());
+
+        assert!(library.delete(AssetKind::Lut, &lut.id).unwrap());
+        assert!(!library.delete(AssetKind::Lut, &lut.id).unwrap());
+        assert!(library.read(AssetKind::Lut, &lut.id).unwrap().is_none());
+        assert!(library.metadata(AssetKind::Lut, &lut.id).unwrap().is_none());
+        assert!(library.list(AssetKind::Lut).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_uploads() {
+        let library = TempLibrary::new();
+        let library = &library.0;
+        assert!(
+            library
+                .upload(AssetKind::Lut, None, b"LUT_3D_SIZE 2\n")
+                .is_err()
+        );
+        assert!(
+            library
+                .upload(AssetKind::Overlay, None, b"not an image")
+                .is_err()
+        );
+        assert!(
+            library
+                .upload(AssetKind::Font, None, b"not a font")
+                .is_err()
+        );
+        assert!(!library.root.exists());
+    }
+
+    #[test]
+    fn resolves_asset_references() {
+        let library = TempLibrary::new();
+        let library = &library.0;
+        let lut = library
+            .upload(AssetKind::Lut, None, CUBE.as_bytes())
+            .unwrap();
+
+        let reference = format!("{}{}", ASSET_SCHEME, lut.id);
+        assert_eq!(
+            library
+                .resolve(AssetKind::Lut, Path::new(&reference))
+                .unwrap(),
+            library.root.join("luts").join(&lut.id)
+        );
+
+        let error = |reference: &str| {
+            library
+                .resolve(AssetKind::Lut, Path::new(reference))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(error("asset://00ff"), "Unknown luts asset 00ff");
+        assert_eq!(error("asset://../luts/x"), "Unknown luts asset ../luts/x");
+        assert_eq!(error("asset://"), "Unknown luts asset ");
+        // Another kind's asset isn't found either.
+        assert!(
+            library
+                .resolve(AssetKind::Overlay, Path::new(&reference))
+                .is_err()
+        );
+
+        // Plain paths go to the local resolver.
+        assert_eq!(
+            library
+                .resolve(AssetKind::Font, Path::new("Roboto.ttf"))
+                .unwrap(),
+            Path::new("assets/fonts/Roboto.ttf")
+        );
+    }
+}
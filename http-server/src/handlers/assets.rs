@@ -0,0 +1,108 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{self, HeaderValue};
+use hyper::{Request, Response, StatusCode};
+
+use super::{error, json};
+use crate::assets::parse_kind;
+use crate::router::{HttpResponse, Params, empty};
+use crate::state::AppState;
+
+macro_rules! asset_kind {
+    ($params:expr) => {
+        match $params.get("kind").and_then(parse_kind) {
+            Some(kind) => kind,
+            None => return Ok(empty(StatusCode::NOT_FOUND)),
+        }
+    };
+}
+
+/// `POST /api/v1/assets/{kind}?name=<file name>` with the raw file as the body.
+pub async fn upload_asset(
+    state: Arc<AppState>,
+    req: Request<Incoming>,
+    params: Params,
+) -> Result<HttpResponse> {
+    let kind = asset_kind!(params);
+    let name = params.query("name").map(String::from);
+    let body = req.collect().await?.to_bytes();
+
+    match smol::unblock(move || state.assets.upload(kind, name, &body)).await {
+        Ok(asset) => json(StatusCode::CREATED, &asset),
+        Err(e) => error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+/// `GET /api/v1/assets/{kind}`
+pub async fn list_assets(
+    state: Arc<AppState>,
+    _: Request<Incoming>,
+    params: Params,
+) -> Result<HttpResponse> {
+    let kind = asset_kind!(params);
+    let assets = smol::unblock(move || state.assets.list(kind)).await?;
+    json(StatusCode::OK, &assets)
+}
+
+/// `GET /api/v1/assets/{kind}/{id}` returns the file itself.
+pub async fn fetch_asset(
+    state: Arc<AppState>,
+    _: Request<Incoming>,
+    params: Params,
+) -> Result<HttpResponse> {
+    let kind = asset_kind!(params);
+    let id = params.get("id").unwrap_or_default().to_string();
+
+    let found = smol::unblock(move || -> Result<_> {
+        Ok((
+            state.assets.metadata(kind, &id)?,
+            state.assets.read(kind, &id)?,
+        ))
+    });
+    let (Some(asset), Some(data)) = found.await? else {
+        return Ok(empty(StatusCode::NOT_FOUND));
+    };
+
+    let content_type = asset
+        .format
+        .as_deref()
+        .and_then(image::ImageFormat::from_extension)
+        .map_or("application/octet-stream", |format| format.to_mime_type());
+
+    let mut res = Response::new(Full::new(Bytes::from(data)));
+    res.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    Ok(res)
+}
+
+/// `GET /api/v1/assets/{kind}/{id}/metadata`
+pub async fn asset_metadata(
+    state: Arc<AppState>,
+    _: Request<Incoming>,
+    params: Params,
+) -> Result<HttpResponse> {
+    let kind = asset_kind!(params);
+    let id = params.get("id").unwrap_or_default().to_string();
+    match smol::unblock(move || state.assets.metadata(kind, &id)).await? {
+        Some(asset) => json(StatusCode::OK, &asset),
+        None => Ok(empty(StatusCode::NOT_FOUND)),
+    }
+}
+
+/// `DELETE /api/v1/assets/{kind}/{id}`
+pub async fn delete_asset(
+    state: Arc<AppState>,
+    _: Request<Incoming>,
+    params: Params,
+) -> Result<HttpResponse> {
+    let kind = asset_kind!(params);
+    let id = params.get("id").unwrap_or_default().to_string();
+    if smol::unblock(move || state.assets.delete(kind, &id)).await? {
+        Ok(empty(StatusCode::NO_CONTENT))
+    } else {
+        Ok(empty(StatusCode::NOT_FOUND))
+    }
+}
@@ -0,0 +1,347 @@
+use core::cancel::CancellationToken;
+use core::config::Config;
+use core::processor::{ImageProcessor, RenderContext};
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow, bail};
+use futures_util::future::join_all;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{self, HeaderValue};
+use hyper::{Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use zip::write::SimpleFileOptions;
+
+use super::{error, json, parse_config};
+use crate::patch::{self, PatchOperation};
+use crate::router::{HttpResponse, Params};
+use crate::state::AppState;
+
+/// Body of `POST /api/v1/batch`: either a list of full configs, or a base
+/// config plus one list of patch operations per variant.
+#[derive(Debug)]
+enum BatchRequest {
+    Configs {
+        configs: Vec<Value>,
+    },
+    Overrides {
+        base: Value,
+        overrides: Vec<Vec<PatchOperation>>,
+    },
+}
+
+/// The body as sent, before checking which form it takes.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchBody {
+    configs: Option<Vec<Value>>,
+    base: Option<Value>,
+    overrides: Option<Vec<Vec<PatchOperation>>>,
+}
+
+impl BatchRequest {
+    fn parse(body: &[u8]) -> Result<Self> {
+        let body: BatchBody = serde_json::from_slice(body)?;
+        match (body.configs, body.base, body.overrides) {
+            (Some(configs), None, None) => Ok(BatchRequest::Configs { configs }),
+            (None, Some(base), Some(overrides)) => Ok(BatchRequest::Overrides { base, overrides }),
+            (Some(_), _, _) => bail!("Send either `configs` or `base` with `overrides`, not both"),
+            (None, Some(_), None) => bail!("`base` needs `overrides`"),
+            (None, None, Some(_)) => bail!("`overrides` needs a `base`"),
+            (None, None, None) => bail!("Send either `configs` or `base` with `overrides`"),
+        }
+    }
+
+    /// Expands the request into one config (or the error building it) per item.
+    fn into_configs(self, state: &AppState) -> Vec<Result<Config>> {
+        let parse = |config| parse_config(state, config);
+
+        match self {
+            BatchRequest::Configs { configs } => configs.into_iter().map(parse).collect(),
+            BatchRequest::Overrides { base, overrides } => overrides
+                .iter()
+                .map(|operations| {
+                    let mut config = base.clone();
+                    patch::apply(&mut config, operations)?;
+                    parse(config)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Per-item entry of the batch manifest.
+#[derive(Debug, Serialize)]
+struct BatchItem {
+    index: usize,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    /// The seed the item was rendered with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Manifest {
+    items: Vec<BatchItem>,
+}
+
+/// What a rendered item produced.
+enum Rendered {
+    /// Saved to the config's output destination.
+    Saved(String),
+    /// Encoded in memory, to be put into the archive.
+    Encoded(String, Vec<u8>),
+}
+
+/// Renders many configs in parallel on the worker pool.
+///
+/// With `?format=zip` the images are returned in a ZIP archive together with
+/// `manifest.json`; otherwise every image is saved to its destination and the
+/// response is the JSON manifest. Items sharing a destination get their index
+/// prefixed to the file name.
+pub async fn batch(
+    state: Arc<AppState>,
+    req: Request<Incoming>,
+    params: Params,
+) -> Result<HttpResponse> {
+    let as_zip = match params.query("format").unwrap_or("json") {
+        "json" => false,
+        "zip" => true,
+        other => return error(StatusCode::BAD_REQUEST, format!("Unknown format {}", other)),
+    };
+
+    let body = req.collect().await?.to_bytes();
+    let request = match BatchRequest::parse(&body) {
+        Ok(request) => request,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+
+    let mut configs = request.into_configs(&state);
+    if configs.is_empty() {
+        return error(StatusCode::BAD_REQUEST, "Batch is empty");
+    }
+    if !as_zip {
+        separate_destinations(&mut configs);
+    }
+
+    let seeds: Vec<_> = configs
+        .iter()
+        .map(|config| config.as_ref().ok().and_then(|config| config.seed))
+        .collect();
+
+    // All items share the request's deadline.
+    let context = state.render_context(&CancellationToken::new());
+    let renders = configs.into_iter().enumerate().map(|(index, config)| {
+        let state = state.clone();
+        let context = context.clone();
+        async move {
+            let config = config?;
+            state
+                .workers
+                .run(move || render(index, &config, &context, as_zip))
+                .await
+        }
+    });
+    let results = join_all(renders).await;
+
+    let mut files = Vec::new();
+    let items = results
+        .into_iter()
+        .enumerate()
+        .map(|(index, result)| match result {
+            Ok(rendered) => {
+                let file = match rendered {
+                    Rendered::Saved(destination) => destination,
+                    Rendered::Encoded(name, bytes) => {
+                        files.push((name.clone(), bytes));
+                        name
+                    }
+                };
+                BatchItem {
+                    index,
+                    status: "ok",
+                    file: Some(file),
+                    seed: seeds[index],
+                    error: None,
+                }
+            }
+            Err(e) => BatchItem {
+                index,
+                status: "error",
+                file: None,
+                seed: seeds[index],
+                error: Some(e.to_string()),
+            },
+        })
+        .collect();
+    let manifest = Manifest { items };
+
+    if !as_zip {
+        return json(StatusCode::OK, &manifest);
+    }
+
+    let archive = build_archive(&manifest, files)?;
+    let mut res = Response::new(Full::new(Bytes::from(archive)));
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/zip"),
+    );
+    res.headers_mut().insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_static("attachment; filename=\"batch.zip\""),
+    );
+    Ok(res)
+}
+
+fn render(
+    index: usize,
+    config: &Config,
+    context: &RenderContext,
+    encode: bool,
+) -> Result<Rendered> {
+    let img = ImageProcessor::process_with(config, context)?;
+
+    if !encode {
+        ImageProcessor::save_image_with(&img, &config.output, &*context.storage)?;
+        return Ok(Rendered::Saved(
+            config.output.destination.display().to_string(),
+        ));
+    }
+
+    let bytes = ImageProcessor::encode_image(&img, &config.output)?;
+    let extension = ImageProcessor::determine_format(&config.output)
+        .extensions_str()
+        .first()
+        .ok_or_else(|| anyhow!("Output format has no file extension"))?;
+    let stem = config
+        .output
+        .destination
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "image".to_string());
+
+    // Prefix with the index so variants sharing a destination don't collide.
+    Ok(Rendered::Encoded(
+        format!("{:03}_{}.{}", index, stem, extension),
+        bytes,
+    ))
+}
+
+/// Prefixes the file name of destinations shared by several items with the
+/// item's index, as in the archive, so saved variants don't overwrite each other.
+fn separate_destinations(configs: &mut [Result<Config>]) {
+    let mut counts = HashMap::new();
+    for config in configs.iter().flatten() {
+        *counts.entry(config.output.destination.clone()).or_insert(0) += 1;
+    }
+
+    for (index, config) in configs.iter_mut().enumerate() {
+        let Ok(config) = config else {
+            continue;
+        };
+        let destination = &mut config.output.destination;
+        if counts[destination] > 1
+            && let Some(name) = destination.file_name()
+        {
+            let name = format!("{:03}_{}", index, name.to_string_lossy());
+            destination.set_file_name(name);
+        }
+    }
+}
+
+fn build_archive(manifest: &Manifest, files: Vec<(String, Vec<u8>)>) -> Result<Vec<u8>> {
+    let mut archive = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    // Encoded images are already compressed.
+    let stored = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+
+    for (name, bytes) in files {
+        archive.start_file(name, stored)?;
+        archive.write_all(&bytes)?;
+    }
+
+    archive.start_file("manifest.json", SimpleFileOptions::default())?;
+    archive.write_all(&serde_json::to_vec_pretty(manifest)?)?;
+
+    Ok(archive.finish()?.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(destination: &str) -> Result<Config> {
+        Ok(serde_json::from_value(serde_json::json!({
+            "version": "1",
+            "input": { "source": "in.png" },
+            "output": { "destination": destination },
+            "operations": [],
+        }))?)
+    }
+
+    #[test]
+    fn parses_both_request_forms() {
+        let request = BatchRequest::parse(br#"{"configs": [{}, {}]}"#).unwrap();
+        assert!(matches!(request, BatchRequest::Configs { configs } if configs.len() == 2));
+
+        let request = BatchRequest::parse(
+            br#"{"base": {}, "overrides": [[{"op": "remove", "path": "/a"}]]}"#,
+        )
+        .unwrap();
+        assert!(
+            matches!(request, BatchRequest::Overrides { overrides, .. } if overrides.len() == 1)
+        );
+    }
+
+    #[test]
+    fn explains_malformed_requests() {
+        let message = |body: &[u8]| BatchRequest::parse(body).unwrap_err().to_string();
+
+        assert_eq!(message(br#"{"base": {}}"#), "`base` needs `overrides`");
+        assert_eq!(
+            message(br#"{}"#),
+            "Send either `configs` or `base` with `overrides`"
+        );
+        assert!(message(br#"{"config": []}"#).contains("unknown field `config`"));
+        assert!(
+            message(br#"{"base": {}, "overrides": [[{"op": "move"}]]}"#)
+                .contains("unknown variant `move`")
+        );
+    }
+
+    #[test]
+    fn prefixes_shared_destinations_only() {
+        let mut configs = vec![
+            config("out/a.png"),
+            config("out/b.png"),
+            Err(anyhow!("broken")),
+            config("out/a.png"),
+        ];
+        separate_destinations(&mut configs);
+
+        let destinations: Vec<_> = configs
+            .iter()
+            .map(|config| {
+                config
+                    .as_ref()
+                    .ok()
+                    .map(|config| config.output.destination.display().to_string())
+            })
+            .collect();
+        assert_eq!(
+            destinations,
+            [
+                Some("out/000_a.png".to_string()),
+                Some("out/b.png".to_string()),
+                None,
+                Some("out/003_a.png".to_string()),
+            ]
+        );
+    }
+}
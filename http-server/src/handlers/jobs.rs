@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use hyper::{Request, StatusCode};
+use serde_json::Value;
+
+use super::{error, json, parse_config};
+use crate::jobs::{self, JobStatus};
+use crate::router::{HttpResponse, Params, empty};
+use crate::state::AppState;
+use crate::webhook;
+
+/// `GET /api/v1/jobs?status=queued&limit=100` lists jobs, newest first.
+pub async fn list_jobs(
+    state: Arc<AppState>,
+    _: Request<Incoming>,
+    params: Params,
+) -> Result<HttpResponse> {
+    let status = match params.query("status").map(str::parse).transpose() {
+        Ok(status) => status,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+    let limit = match params.query("limit").map(str::parse::<usize>).transpose() {
+        Ok(limit) => limit.unwrap_or(100).min(1000),
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+    let jobs = smol::unblock(move || state.jobs.list(status, limit)).await?;
+    json(StatusCode::OK, &jobs)
+}
+
+/// `POST /api/v1/jobs` with a generate config and an optional `callback_url`.
+///
+/// Answers `202 Accepted` with the queued job right away.
+pub async fn submit_job(
+    state: Arc<AppState>,
+    req: Request<Incoming>,
+    _: Params,
+) -> Result<HttpResponse> {
+    let body = req.collect().await?.to_bytes();
+    let parsed = serde_json::from_slice::<Value>(&body)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut config| {
+            let callback_url = match config
+                .as_object_mut()
+                .and_then(|c| c.remove("callback_url"))
+            {
+                Some(Value::String(url)) => {
+                    webhook::parse_url(&url)?;
+                    Some(url)
+                }
+                Some(_) => return Err(anyhow!("callback_url must be a string")),
+                None => None,
+            };
+            Ok((parse_config(&state, config)?, callback_url))
+        });
+    let (config, callback_url) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+
+    let job = smol::unblock(move || jobs::submit(&state, config, callback_url)).await?;
+    json(StatusCode::ACCEPTED, &job)
+}
+
+/// `POST /api/v1/jobs/{id}/cancel`
+///
+/// Answers `202 Accepted` with the job; a running job switches to `cancelled`
+/// once it stops. Finished jobs can't be cancelled.
+pub async fn cancel_job(
+    state: Arc<AppState>,
+    _: Request<Incoming>,
+    params: Params,
+) -> Result<HttpResponse> {
+    let id = params.get("id").unwrap_or_default().to_string();
+    match smol::unblock(move || jobs::cancel(&state, &id)).await? {
+        Some(job) if matches!(job.status, JobStatus::Succeeded | JobStatus::Failed) => {
+            error(StatusCode::CONFLICT, "Job already finished")
+        }
+        Some(job) => json(StatusCode::ACCEPTED, &job),
+        None => Ok(empty(StatusCode::NOT_FOUND)),
+    }
+}
+
+/// `GET /api/v1/jobs/{id}`
+pub async fn get_job(
+    state: Arc<AppState>,
+    _: Request<Incoming>,
+    params: Params,
+) -> Result<HttpResponse> {
+    let id = params.get("id").unwrap_or_default().to_string();
+    match smol::unblock(move || state.jobs.get(&id)).await? {
+        Some(job) => json(StatusCode::OK, &job),
+        None => Ok(empty(StatusCode::NOT_FOUND)),
+    }
+}
@@ -0,0 +1,125 @@
+use core::cancel::{CancellationToken, Cancelled};
+use core::{config, processor};
+use std::sync::Arc;
+
+use anyhow::Result;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{self, HeaderValue};
+use hyper::{Request, Response, StatusCode};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::router::{HttpResponse, Params};
+use crate::state::AppState;
+use crate::workers::Panicked;
+
+/// Response header carrying the seed a render used, to recreate it later.
+pub const SEED_HEADER: &str = "x-ccimg-seed";
+
+mod assets;
+pub use assets::{asset_metadata, delete_asset, fetch_asset, list_assets, upload_asset};
+
+mod batch;
+pub use batch::batch;
+
+mod jobs;
+pub use jobs::{cancel_job, get_job, list_jobs, submit_job};
+
+mod preview;
+pub use preview::preview;
+
+mod presets;
+pub use presets::{delete_preset, get_preset, list_presets, put_preset};
+
+pub async fn generate(
+    state: Arc<AppState>,
+    req: Request<Incoming>,
+    _: Params,
+) -> Result<HttpResponse> {
+    let body = req.collect().await?.to_bytes();
+    let config = match serde_json::from_slice(&body)
+        .map_err(anyhow::Error::from)
+        .and_then(|config| parse_config(&state, config))
+    {
+        Ok(config) => config,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+    let seed = config.seed.unwrap_or_default();
+    let context = state.render_context(&CancellationToken::new());
+    let rendered = state
+        .workers
+        .run(move || {
+            let processed_image = processor::ImageProcessor::process_with(&config, &context)?;
+            processor::ImageProcessor::save_image_with(
+                &processed_image,
+                &config.output,
+                &*context.storage,
+            )
+        })
+        .await;
+    if let Err(e) = rendered {
+        return render_error(e);
+    }
+
+    let mut res = Response::new(Full::new(Bytes::new()));
+    *res.status_mut() = StatusCode::OK;
+    res.headers_mut()
+        .insert(SEED_HEADER, HeaderValue::from(seed));
+    Ok(res)
+}
+
+/// Parses a request config, expanding a referenced preset. Configs without a
+/// seed get a random one, so the response can report it.
+fn parse_config(state: &AppState, mut config: Value) -> Result<config::Config> {
+    state.presets.expand(&mut config)?;
+    let mut config: config::Config = serde_json::from_value(config)?;
+    config
+        .seed
+        .get_or_insert_with(processor::ImageProcessor::new_seed);
+    Ok(config)
+}
+
+/// Builds a JSON response.
+fn json<T: Serialize>(status: StatusCode, value: &T) -> Result<HttpResponse> {
+    let mut res = Response::new(Full::new(Bytes::from(serde_json::to_vec(value)?)));
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    Ok(res)
+}
+
+/// Builds a JSON error response of the form `{"error": "..."}`.
+fn error(status: StatusCode, message: impl ToString) -> Result<HttpResponse> {
+    json(status, &serde_json::json!({ "error": message.to_string() }))
+}
+
+/// Builds the response for a failed render: `500` with a correlation ID for
+/// panics, `503` for timeouts and `422` for everything else.
+fn render_error(e: anyhow::Error) -> Result<HttpResponse> {
+    if let Some(panicked) = e.downcast_ref::<Panicked>() {
+        return internal_error(panicked);
+    }
+    if e.is::<Cancelled>() {
+        return error(StatusCode::SERVICE_UNAVAILABLE, e);
+    }
+    error(StatusCode::UNPROCESSABLE_ENTITY, e)
+}
+
+/// Builds the `500` response for a handler that returned an error.
+pub fn handler_error(e: &anyhow::Error) -> Result<HttpResponse> {
+    error(StatusCode::INTERNAL_SERVER_ERROR, e)
+}
+
+/// Builds the `500` response for a panic, including its correlation ID.
+pub fn internal_error(panicked: &Panicked) -> Result<HttpResponse> {
+    json(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        &serde_json::json!({
+            "error": panicked.to_string(),
+            "correlation_id": panicked.correlation_id,
+        }),
+    )
+}
@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use hyper::{Request, StatusCode};
+
+use super::{error, json};
+use crate::presets::Preset;
+use crate::router::{HttpResponse, Params, empty};
+use crate::state::AppState;
+
+/// `GET /api/v1/presets`
+pub async fn list_presets(
+    state: Arc<AppState>,
+    _: Request<Incoming>,
+    _: Params,
+) -> Result<HttpResponse> {
+    json(StatusCode::OK, &state.presets.names()?)
+}
+
+/// `GET /api/v1/presets/{name}`
+pub async fn get_preset(
+    state: Arc<AppState>,
+    _: Request<Incoming>,
+    params: Params,
+) -> Result<HttpResponse> {
+    match state.presets.get(params.get("name").unwrap_or_default()) {
+        Ok(Some(preset)) => json(StatusCode::OK, &preset),
+        Ok(None) => Ok(empty(StatusCode::NOT_FOUND)),
+        Err(e) => error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+/// `PUT /api/v1/presets/{name}` with `{"parameters": {...}, "operations": [...]}`.
+pub async fn put_preset(
+    state: Arc<AppState>,
+    req: Request<Incoming>,
+    params: Params,
+) -> Result<HttpResponse> {
+    let body = req.collect().await?.to_bytes();
+    let preset: Preset = match serde_json::from_slice(&body) {
+        Ok(preset) => preset,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+
+    match state
+        .presets
+        .put(params.get("name").unwrap_or_default(), &preset)
+    {
+        Ok(true) => Ok(empty(StatusCode::NO_CONTENT)),
+        Ok(false) => Ok(empty(StatusCode::CREATED)),
+        Err(e) => error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+/// `DELETE /api/v1/presets/{name}`
+pub async fn delete_preset(
+    state: Arc<AppState>,
+    _: Request<Incoming>,
+    params: Params,
+) -> Result<HttpResponse> {
+    match state.presets.delete(params.get("name").unwrap_or_default()) {
+        Ok(true) => Ok(empty(StatusCode::NO_CONTENT)),
+        Ok(false) => Ok(empty(StatusCode::NOT_FOUND)),
+        Err(e) => error(StatusCode::BAD_REQUEST, e),
+    }
+}
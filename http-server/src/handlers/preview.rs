@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_tungstenite::WebSocketStream;
+use async_tungstenite::tungstenite::handshake::derive_accept_key;
+use async_tungstenite::tungstenite::protocol::Role;
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{self, HeaderValue};
+use hyper::{Request, Response, StatusCode};
+use tracing::Instrument;
+
+use super::error;
+use crate::preview;
+use crate::router::{HttpResponse, Params};
+use crate::state::AppState;
+use crate::stream::UpgradedStream;
+
+/// `GET /api/v1/preview` upgrades to a WebSocket live-preview session.
+pub async fn preview(
+    state: Arc<AppState>,
+    mut req: Request<Incoming>,
+    _: Params,
+) -> Result<HttpResponse> {
+    let is_websocket = req
+        .headers()
+        .get(header::UPGRADE)
+        .is_some_and(|u| u.as_bytes().eq_ignore_ascii_case(b"websocket"));
+    let Some(key) = req.headers().get(header::SEC_WEBSOCKET_KEY) else {
+        return error(StatusCode::BAD_REQUEST, "Expected a WebSocket upgrade");
+    };
+    if !is_websocket {
+        return error(StatusCode::BAD_REQUEST, "Expected a WebSocket upgrade");
+    }
+    let accept = derive_accept_key(key.as_bytes());
+
+    let upgrade = hyper::upgrade::on(&mut req);
+    let session_state = state.clone();
+    state
+        .executor
+        .spawn(
+            async move {
+                let result = match upgrade.await {
+                    Ok(upgraded) => {
+                        let ws = WebSocketStream::from_raw_socket(
+                            UpgradedStream(upgraded),
+                            Role::Server,
+                            None,
+                        )
+                        .await;
+                        preview::run(session_state, ws).await
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    tracing::warn!(error = %e, "Preview session failed");
+                }
+            }
+            .instrument(tracing::info_span!("preview")),
+        )
+        .detach();
+
+    let mut res = Response::new(Full::new(Bytes::new()));
+    *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = res.headers_mut();
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(
+        header::SEC_WEBSOCKET_ACCEPT,
+        HeaderValue::from_str(&accept)?,
+    );
+    Ok(res)
+}
@@ -0,0 +1,679 @@
+use core::cancel::{CancellationToken, Cancelled};
+use core::config::Config;
+use core::processor::ImageProcessor;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, bail};
+use rand::Rng;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+use rusqlite::{Connection, OptionalExtension, Row, ToSql, params};
+use serde::Serialize;
+use smol::Timer;
+use tracing::Instrument;
+
+use crate::state::AppState;
+use crate::webhook;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "queued" => JobStatus::Queued,
+            "running" => JobStatus::Running,
+            "succeeded" => JobStatus::Succeeded,
+            "failed" => JobStatus::Failed,
+            "cancelled" => JobStatus::Cancelled,
+            _ => bail!("Unknown job status {}", s),
+        })
+    }
+}
+
+impl ToSql for JobStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl FromSql for JobStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: anyhow::Error| FromSqlError::Other(e.into()))
+    }
+}
+
+/// An asynchronous render job. This is also the webhook payload.
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    /// Timestamps in milliseconds since the Unix epoch.
+    pub submitted_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+    /// Where the rendered image was saved.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub callback_url: Option<String>,
+    /// The seed the job renders with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+/// Columns read by `Job::from_row`, in order.
+const COLUMNS: &str =
+    "id, status, submitted_at, started_at, finished_at, result, error, callback_url, seed";
+
+impl Job {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            status: row.get(1)?,
+            submitted_at: row.get(2)?,
+            started_at: row.get(3)?,
+            finished_at: row.get(4)?,
+            result: row.get(5)?,
+            error: row.get(6)?,
+            callback_url: row.get(7)?,
+            // Stored as a signed integer, SQLite has no unsigned 64-bit type.
+            seed: row.get::<_, Option<i64>>(8)?.map(|seed| seed as u64),
+        })
+    }
+}
+
+/// Schema changes in order. The database's `user_version` counts the ones applied.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS jobs (
+        id TEXT PRIMARY KEY,
+        status TEXT NOT NULL,
+        config TEXT NOT NULL,
+        submitted_at INTEGER NOT NULL,
+        started_at INTEGER,
+        finished_at INTEGER,
+        result TEXT,
+        error TEXT,
+        callback_url TEXT
+    );
+    CREATE INDEX IF NOT EXISTS jobs_by_status ON jobs (status, submitted_at);",
+    "ALTER TABLE jobs ADD COLUMN seed INTEGER;",
+];
+
+/// Registry of submitted jobs, persisted in SQLite together with their
+/// configs so queued jobs survive a restart.
+///
+/// The methods block on the database, so async code calls them (and
+/// `submit` and `cancel`) through `smol::unblock`.
+pub struct Jobs {
+    db: Mutex<Connection>,
+    /// Cancellation tokens of the jobs queued or running in this process.
+    tokens: Mutex<HashMap<String, CancellationToken>>,
+}
+
+impl Jobs {
+    /// Opens (or creates) the database.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// Uses an open database, applying the migrations it is missing.
+    fn with_connection(db: Connection) -> Result<Self> {
+        let version: usize = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            db.execute_batch(migration)?;
+            db.pragma_update(None, "user_version", index + 1)?;
+        }
+        Ok(Self {
+            db: Mutex::new(db),
+            tokens: Mutex::default(),
+        })
+    }
+
+    /// Locks the connection. A panic while it was held can't leave it
+    /// half-written, as every change is a single statement.
+    fn db(&self) -> MutexGuard<'_, Connection> {
+        self.db.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn tokens(&self) -> MutexGuard<'_, HashMap<String, CancellationToken>> {
+        self.tokens.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<Job>> {
+        let db = self.db();
+        Ok(db
+            .query_row(
+                &format!("SELECT {} FROM jobs WHERE id = ?1", COLUMNS),
+                [id],
+                Job::from_row,
+            )
+            .optional()?)
+    }
+
+    /// Lists jobs newest first, optionally only those with `status`.
+    pub fn list(&self, status: Option<JobStatus>, limit: usize) -> Result<Vec<Job>> {
+        let db = self.db();
+        let mut query = db.prepare(&format!(
+            "SELECT {} FROM jobs WHERE ?1 IS NULL OR status = ?1
+             ORDER BY submitted_at DESC LIMIT ?2",
+            COLUMNS
+        ))?;
+        let jobs = query
+            .query_map(params![status, limit as i64], Job::from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(jobs)
+    }
+
+    fn insert(&self, job: &Job, config: &Config) -> Result<()> {
+        let db = self.db();
+        db.execute(
+            "INSERT INTO jobs (id, status, config, submitted_at, callback_url, seed)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                job.id,
+                job.status,
+                serde_json::to_string(config)?,
+                job.submitted_at,
+                job.callback_url,
+                job.seed.map(|seed| seed as i64),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Applies `f` to the job, saves it and returns the updated copy.
+    fn update(&self, id: &str, f: impl FnOnce(&mut Job)) -> Result<Option<Job>> {
+        let db = self.db();
+        let Some(mut job) = db
+            .query_row(
+                &format!("SELECT {} FROM jobs WHERE id = ?1", COLUMNS),
+                [id],
+                Job::from_row,
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        f(&mut job);
+        db.execute(
+            "UPDATE jobs SET status = ?2, started_at = ?3, finished_at = ?4, result = ?5, error = ?6
+             WHERE id = ?1",
+            params![
+                job.id,
+                job.status,
+                job.started_at,
+                job.finished_at,
+                job.result,
+                job.error,
+            ],
+        )?;
+        Ok(Some(job))
+    }
+
+    /// Returns the jobs that didn't finish, oldest first, with their configs.
+    /// Running jobs were interrupted by a restart and go back to the queue.
+    fn unfinished(&self) -> Result<Vec<(Job, String)>> {
+        let db = self.db();
+        db.execute(
+            "UPDATE jobs SET status = ?1, started_at = NULL WHERE status = ?2",
+            params![JobStatus::Queued, JobStatus::Running],
+        )?;
+        let mut query = db.prepare(&format!(
+            "SELECT {}, config FROM jobs WHERE status = ?1 ORDER BY submitted_at",
+            COLUMNS
+        ))?;
+        let jobs = query
+            .query_map([JobStatus::Queued], |row| {
+                Ok((Job::from_row(row)?, row.get(9)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(jobs)
+    }
+
+    /// Whether a job still in the store wrote, or is going to write, `path`.
+    fn references(&self, path: &str) -> Result<bool> {
+        let db = self.db();
+        Ok(db.query_row(
+            "SELECT EXISTS (SELECT 1 FROM jobs
+             WHERE result = ?1 OR json_extract(config, '$.output.destination') = ?1)",
+            [path],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Deletes the jobs that finished before `before` and returns them.
+    fn remove_finished_before(&self, before: u64) -> Result<Vec<Job>> {
+        let db = self.db();
+        let mut query = db.prepare(&format!(
+            "DELETE FROM jobs WHERE finished_at < ?1 RETURNING {}",
+            COLUMNS
+        ))?;
+        let jobs = query
+            .query_map([before], Job::from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(jobs)
+    }
+}
+
+/// Queues a render and returns the new job. The job runs on the worker pool;
+/// when it finishes its callback URL (if any) is notified.
+pub fn submit(state: &Arc<AppState>, config: Config, callback_url: Option<String>) -> Result<Job> {
+    let job = Job {
+        id: format!("{:016x}", rand::thread_rng().r#gen::<u64>()),
+        status: JobStatus::Queued,
+        submitted_at: now(),
+        started_at: None,
+        finished_at: None,
+        result: None,
+        error: None,
+        callback_url,
+        seed: config.seed,
+    };
+    state.jobs.insert(&job, &config)?;
+    run(state, job.id.clone(), config);
+    Ok(job)
+}
+
+/// Queues the jobs left unfinished by the previous run.
+pub fn recover(state: &Arc<AppState>) -> Result<()> {
+    for (job, config) in state.jobs.unfinished()? {
+        match serde_json::from_str(&config) {
+            Ok(config) => run(state, job.id, config),
+            Err(e) => {
+                state.jobs.update(&job.id, |job| {
+                    job.status = JobStatus::Failed;
+                    job.finished_at = Some(now());
+                    job.error = Some(format!("Could not recover the config: {}", e));
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Spawns the render of a queued job.
+fn run(state: &Arc<AppState>, id: String, config: Config) {
+    let cancel = CancellationToken::new();
+    state.jobs.tokens().insert(id.clone(), cancel.clone());
+
+    let task_state = state.clone();
+    let span = tracing::info_span!("job", id = %id);
+    state
+        .executor
+        .spawn(
+            async move {
+                let state = task_state;
+                let update_state = state.clone();
+                let started_id = id.clone();
+                let result = state
+                    .workers
+                    .run(move || {
+                        // The timeouts start once a worker picks the job up.
+                        let context = update_state.render_context(&cancel);
+                        context.cancel.check()?;
+                        update_state.jobs.update(&started_id, |job| {
+                            if job.status == JobStatus::Queued {
+                                job.status = JobStatus::Running;
+                                job.started_at = Some(now());
+                            }
+                        })?;
+                        let img = ImageProcessor::process_with(&config, &context)?;
+                        ImageProcessor::save_image_with(&img, &config.output, &*context.storage)?;
+                        anyhow::Ok(config.output.destination.display().to_string())
+                    })
+                    .await;
+                state.jobs.tokens().remove(&id);
+
+                let update_state = state.clone();
+                let finished = smol::unblock(move || {
+                    update_state.jobs.update(&id, |job| {
+                        job.finished_at = Some(now());
+                        match result {
+                            Ok(destination) => {
+                                job.status = JobStatus::Succeeded;
+                                job.result = Some(destination);
+                            }
+                            Err(e) if e.downcast_ref() == Some(&Cancelled::Requested) => {
+                                job.status = JobStatus::Cancelled;
+                            }
+                            Err(e) => {
+                                job.status = JobStatus::Failed;
+                                job.error = Some(e.to_string());
+                            }
+                        }
+                    })
+                })
+                .await;
+
+                match finished {
+                    Ok(Some(job)) => {
+                        if let Some(url) = &job.callback_url
+                            && let Err(e) = webhook::deliver(&state.settings, url, &job).await
+                        {
+                            tracing::warn!(error = %e, "Callback failed");
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => tracing::error!(error = %e, "Could not save the job"),
+                }
+            }
+            .instrument(span),
+        )
+        .detach();
+}
+
+/// Cancels a queued or running job and returns it. A queued job is marked
+/// cancelled right away, a running one at its next cancellation check.
+pub fn cancel(state: &AppState, id: &str) -> Result<Option<Job>> {
+    if let Some(token) = state.jobs.tokens().get(id) {
+        token.cancel();
+    }
+    state.jobs.update(id, |job| {
+        if job.status == JobStatus::Queued {
+            job.status = JobStatus::Cancelled;
+            job.finished_at = Some(now());
+        }
+    })
+}
+
+/// Every hour, deletes the jobs that finished longer than the retention
+/// period ago along with their results. A result is kept while a newer job
+/// uses the same destination. Does nothing without a retention period.
+pub fn spawn_cleanup(state: &Arc<AppState>) {
+    let Some(hours) = state.settings.job_retention_hours else {
+        return;
+    };
+    let retention = hours * 60 * 60 * 1000;
+
+    let task_state = state.clone();
+    state
+        .executor
+        .spawn(async move {
+            loop {
+                let state = task_state.clone();
+                let before = now().saturating_sub(retention);
+                match smol::unblock(move || cleanup(&state, before)).await {
+                    Ok(0) => {}
+                    Ok(removed) => tracing::info!(removed, "Removed expired jobs"),
+                    Err(e) => tracing::warn!(error = %e, "Could not remove expired jobs"),
+                }
+                Timer::after(Duration::from_secs(60 * 60)).await;
+            }
+        })
+        .detach();
+}
+
+fn cleanup(state: &AppState, before: u64) -> Result<usize> {
+    let jobs = state.jobs.remove_finished_before(before)?;
+    for job in &jobs {
+        let Some(result) = &job.result else {
+            continue;
+        };
+        // A newer job may have written to the same destination.
+        if state.jobs.references(result)? {
+            continue;
+        }
+        if let Err(e) = state.context.storage.delete(Path::new(result)) {
+            tracing::warn!(id = %job.id, error = %e, "Could not delete job result");
+        }
+    }
+    Ok(jobs.len())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+
+    /// State with an in-memory database and an executor that is never run,
+    /// so submitted jobs stay queued.
+    fn state() -> Arc<AppState> {
+        let settings = Settings {
+            jobs_db: ":memory:".into(),
+            ..Settings::default()
+        };
+        Arc::new(AppState::new(settings, Arc::new(smol::Executor::new())).unwrap())
+    }
+
+    fn config() -> Config {
+        config_to("out.png")
+    }
+
+    fn config_to(destination: &str) -> Config {
+        serde_json::from_value(serde_json::json!({
+            "version": "1",
+            "input": { "source": "in.png" },
+            "output": { "destination": destination },
+            "operations": [],
+        }))
+        .unwrap()
+    }
+
+    fn store() -> Jobs {
+        Jobs::with_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    /// Inserts a job submitted at `submitted_at` with the given status.
+    fn add(jobs: &Jobs, id: &str, submitted_at: u64, status: JobStatus) {
+        let job = Job {
+            id: id.to_string(),
+            status: JobStatus::Queued,
+            submitted_at,
+            started_at: None,
+            finished_at: None,
+            result: None,
+            error: None,
+            callback_url: None,
+            seed: Some(7),
+        };
+        jobs.insert(&job, &config()).unwrap();
+        jobs.update(id, |job| {
+            job.status = status;
+            if status != JobStatus::Queued {
+                job.started_at = Some(submitted_at + 1);
+            }
+            if !matches!(status, JobStatus::Queued | JobStatus::Running) {
+                job.finished_at = Some(submitted_at + 2);
+            }
+        })
+        .unwrap();
+    }
+
+    fn ids(jobs: &[Job]) -> Vec<&str> {
+        jobs.iter().map(|job| job.id.as_str()).collect()
+    }
+
+    #[test]
+    fn migrates_old_databases() {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(MIGRATIONS[0]).unwrap();
+        db.pragma_update(None, "user_version", 1).unwrap();
+        db.execute(
+            "INSERT INTO jobs (id, status, config, submitted_at) VALUES ('old', 'queued', '{}', 1)",
+            [],
+        )
+        .unwrap();
+
+        let jobs = Jobs::with_connection(db).unwrap();
+        let version: usize = jobs
+            .db()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        let old = jobs.get("old").unwrap().unwrap();
+        assert_eq!((old.status, old.seed), (JobStatus::Queued, None));
+
+        // A migrated database is left as it is.
+        let db = jobs.db.into_inner().unwrap();
+        assert!(
+            Jobs::with_connection(db)
+                .unwrap()
+                .get("old")
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn lists_newest_first_and_filters_by_status() {
+        let jobs = store();
+        add(&jobs, "a", 10, JobStatus::Succeeded);
+        add(&jobs, "b", 20, JobStatus::Queued);
+        add(&jobs, "c", 30, JobStatus::Succeeded);
+
+        assert_eq!(ids(&jobs.list(None, 100).unwrap()), ["c", "b", "a"]);
+        assert_eq!(ids(&jobs.list(None, 2).unwrap()), ["c", "b"]);
+        assert_eq!(
+            ids(&jobs.list(Some(JobStatus::Succeeded), 100).unwrap()),
+            ["c", "a"]
+        );
+        assert!(jobs.list(Some(JobStatus::Failed), 100).unwrap().is_empty());
+        assert_eq!(jobs.get("a").unwrap().unwrap().seed, Some(7));
+    }
+
+    #[test]
+    fn requeues_interrupted_jobs_on_recovery() {
+        let jobs = store();
+        add(&jobs, "running", 20, JobStatus::Running);
+        add(&jobs, "queued", 10, JobStatus::Queued);
+        add(&jobs, "done", 5, JobStatus::Succeeded);
+
+        let unfinished = jobs.unfinished().unwrap();
+        let recovered: Vec<_> = unfinished.iter().map(|(job, _)| job.clone()).collect();
+        assert_eq!(ids(&recovered), ["queued", "running"]);
+        assert!(recovered.iter().all(|job| job.status == JobStatus::Queued));
+        assert!(recovered.iter().all(|job| job.started_at.is_none()));
+        let config: Config = serde_json::from_str(&unfinished[0].1).unwrap();
+        assert_eq!(config.output.destination, Path::new("out.png"));
+        assert_eq!(
+            jobs.get("running").unwrap().unwrap().status,
+            JobStatus::Queued
+        );
+    }
+
+    #[test]
+    fn removes_only_jobs_finished_before_the_cutoff() {
+        let jobs = store();
+        add(&jobs, "old", 10, JobStatus::Failed);
+        add(&jobs, "new", 100, JobStatus::Succeeded);
+        add(&jobs, "queued", 1, JobStatus::Queued);
+
+        assert_eq!(ids(&jobs.remove_finished_before(50).unwrap()), ["old"]);
+        assert!(jobs.get("old").unwrap().is_none());
+        assert_eq!(ids(&jobs.list(None, 100).unwrap()), ["new", "queued"]);
+        assert!(jobs.remove_finished_before(50).unwrap().is_empty());
+    }
+
+    #[test]
+    fn cleanup_keeps_results_a_newer_job_uses() {
+        let dir = std::env::temp_dir().join(format!("ccimg-cleanup-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (shared, own) = (dir.join("shared.png"), dir.join("own.png"));
+        fs::write(&shared, b"newer").unwrap();
+        fs::write(&own, b"old").unwrap();
+
+        let state = state();
+        let finish = |destination: &Path, finished_at| {
+            let job = submit(&state, config_to(destination.to_str().unwrap()), None).unwrap();
+            state
+                .jobs
+                .update(&job.id, |job| {
+                    job.status = JobStatus::Succeeded;
+                    job.finished_at = Some(finished_at);
+                    job.result = Some(destination.display().to_string());
+                })
+                .unwrap();
+        };
+        finish(&shared, 1);
+        finish(&own, 1);
+        finish(&shared, 1000);
+
+        assert_eq!(cleanup(&state, 500).unwrap(), 2);
+        assert!(shared.exists());
+        assert!(!own.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn set_status(state: &AppState, id: &str, status: JobStatus) {
+        state.jobs.update(id, |job| job.status = status).unwrap();
+    }
+
+    #[test]
+    fn cancels_queued_jobs_right_away() {
+        let state = state();
+        let job = submit(&state, config(), None).unwrap();
+        let token = state.jobs.tokens()[&job.id].clone();
+
+        let cancelled = cancel(&state, &job.id).unwrap().unwrap();
+        assert_eq!(cancelled.status, JobStatus::Cancelled);
+        assert!(cancelled.finished_at.is_some());
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn running_jobs_stop_at_their_next_check() {
+        let state = state();
+        let job = submit(&state, config(), None).unwrap();
+        set_status(&state, &job.id, JobStatus::Running);
+        let token = state.jobs.tokens()[&job.id].clone();
+
+        let running = cancel(&state, &job.id).unwrap().unwrap();
+        assert_eq!(running.status, JobStatus::Running);
+        assert!(running.finished_at.is_none());
+        assert_eq!(token.check(), Err(Cancelled::Requested));
+    }
+
+    #[test]
+    fn finished_jobs_are_left_alone() {
+        let state = state();
+        let job = submit(&state, config(), None).unwrap();
+        set_status(&state, &job.id, JobStatus::Succeeded);
+        state.jobs.tokens().remove(&job.id);
+
+        let finished = cancel(&state, &job.id).unwrap().unwrap();
+        assert_eq!(finished.status, JobStatus::Succeeded);
+        assert!(cancel(&state, "missing").unwrap().is_none());
+    }
+}
@@ -0,0 +1,31 @@
+use hyper::HeaderMap;
+use rand::Rng;
+use tracing_subscriber::EnvFilter;
+
+use crate::settings::Settings;
+
+/// Header carrying the request ID. A valid ID sent by the client is reused,
+/// otherwise a new one is generated. Either way it is echoed in the response.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Installs the global subscriber. Verbosity is set with `RUST_LOG`
+/// (defaults to `info`); access log lines use the `access` target.
+pub fn init(settings: &Settings) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    if settings.log_json {
+        builder.json().with_span_list(true).init();
+    } else {
+        builder.init();
+    }
+}
+
+/// Returns the request ID for a request.
+pub fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic()))
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:016x}", rand::thread_rng().r#gen::<u64>()))
+}
@@ -1,51 +1,197 @@
-use std::net::{TcpListener, TcpStream};
+use std::fs;
+use std::net::TcpListener;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
 
-use anyhow::Result;
-use async_native_tls::TlsAcceptor;
+use anyhow::{Result, anyhow, bail};
+use async_native_tls::{Identity, TlsAcceptor};
+use futures_util::{FutureExt, future};
 use http_body_util::Full;
-use hyper::body::{Bytes, Incoming};
+use hyper::body::{Body, Bytes, Incoming};
+use hyper::header::HeaderValue;
 use hyper::service::service_fn;
-use hyper::{Method, Request, Response, StatusCode};
+use hyper::{Method, Request, Response};
+use hyper_util::server::conn::auto;
 use macro_rules_attribute::apply;
 use smol::{Async, Executor};
-use smol_hyper::rt::{FuturesIo, SmolTimer};
+use smol_hyper::rt::{FuturesIo, SmolExecutor, SmolTimer};
 use smol_macros::main;
+use tracing::Instrument;
+
+mod assets;
 
 mod stream;
-use stream::SmolStream;
+use stream::{Listener, SmolStream};
 
 mod handlers;
 
-/// Serves a request and returns a response.
-async fn serve(req: Request<Incoming>) -> Result<Response<Full<Bytes>>> {
-    println!("Serving {}", req.uri());
-    match (req.method(), req.uri().to_string().as_str()) {
-        (&Method::POST, "/api/v1/generate") => return handlers::generate(req).await,
-        _ => {
-            let mut res = Response::new(Full::new(Bytes::new()));
-            *res.status_mut() = StatusCode::NOT_FOUND;
-            Ok(res)
-        },
+mod router;
+use router::{Cors, Failure, Router};
+
+mod jobs;
+
+mod logging;
+
+mod patch;
+
+mod presets;
+
+mod preview;
+
+mod settings;
+use settings::Settings;
+
+mod state;
+use state::AppState;
+
+mod webhook;
+
+mod workers;
+use workers::Panicked;
+
+/// Serves a request in its own `request` span and writes an access log line.
+/// A handler that fails or panics is answered with a `500` instead of
+/// dropping the connection.
+async fn serve(
+    router: Arc<Router<AppState>>,
+    req: Request<Incoming>,
+    client: String,
+) -> Result<Response<Full<Bytes>>> {
+    let start = Instant::now();
+    let id = logging::request_id(req.headers());
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let span = tracing::info_span!("request", id = %id);
+
+    let dispatched = AssertUnwindSafe(router.dispatch(req))
+        .catch_unwind()
+        .instrument(span.clone())
+        .await;
+    // Handler failures are answered by the router; this catches the router's own.
+    let mut res = match dispatched {
+        Ok(Ok(res)) => res,
+        Ok(Err(e)) => span.in_scope(|| failure_response(Failure::Error(e)))?,
+        Err(payload) => span.in_scope(|| failure_response(Failure::Panic(payload)))?,
+    };
+    res.headers_mut()
+        .insert(logging::REQUEST_ID_HEADER, HeaderValue::from_str(&id)?);
+
+    tracing::info!(
+        target: "access",
+        parent: &span,
+        client = %client,
+        %method,
+        %path,
+        status = res.status().as_u16(),
+        bytes = res.body().size_hint().exact().unwrap_or(0),
+        latency_ms = start.elapsed().as_secs_f64() * 1000.0,
+    );
+    Ok(res)
+}
+
+/// Logs a failed request and builds its `500` response. A panic gets a
+/// correlation ID that is both logged and sent to the client.
+fn failure_response(failure: Failure) -> Result<Response<Full<Bytes>>> {
+    match failure {
+        Failure::Error(e) => {
+            tracing::error!(error = %e, "Request failed");
+            handlers::handler_error(&e)
+        }
+        Failure::Panic(payload) => {
+            let panicked = Panicked::new();
+            tracing::error!(
+                correlation_id = %panicked.correlation_id,
+                panic = workers::panic_message(&*payload),
+                "Handler panicked"
+            );
+            handlers::internal_error(&panicked)
+        }
     }
 }
 
+/// Builds the API routes.
+fn routes(state: Arc<AppState>) -> Router<AppState> {
+    let cors = Cors {
+        allowed_origins: state.settings.cors_origins.clone(),
+        exposed_headers: vec![
+            handlers::SEED_HEADER.to_string(),
+            logging::REQUEST_ID_HEADER.to_string(),
+        ],
+    };
+
+    Router::new(state, cors)
+        .on_failure(failure_response)
+        .route(Method::POST, "/api/v1/generate", handlers::generate)
+        .route(Method::POST, "/api/v1/batch", handlers::batch)
+        .route(Method::GET, "/api/v1/assets/{kind}", handlers::list_assets)
+        .route(
+            Method::POST,
+            "/api/v1/assets/{kind}",
+            handlers::upload_asset,
+        )
+        .route(
+            Method::GET,
+            "/api/v1/assets/{kind}/{id}",
+            handlers::fetch_asset,
+        )
+        .route(
+            Method::DELETE,
+            "/api/v1/assets/{kind}/{id}",
+            handlers::delete_asset,
+        )
+        .route(
+            Method::GET,
+            "/api/v1/assets/{kind}/{id}/metadata",
+            handlers::asset_metadata,
+        )
+        .route(Method::GET, "/api/v1/jobs", handlers::list_jobs)
+        .route(Method::POST, "/api/v1/jobs", handlers::submit_job)
+        .route(Method::GET, "/api/v1/jobs/{id}", handlers::get_job)
+        .route(
+            Method::POST,
+            "/api/v1/jobs/{id}/cancel",
+            handlers::cancel_job,
+        )
+        .route(Method::GET, "/api/v1/preview", handlers::preview)
+        .route(Method::GET, "/api/v1/presets", handlers::list_presets)
+        .route(Method::GET, "/api/v1/presets/{name}", handlers::get_preset)
+        .route(Method::PUT, "/api/v1/presets/{name}", handlers::put_preset)
+        .route(
+            Method::DELETE,
+            "/api/v1/presets/{name}",
+            handlers::delete_preset,
+        )
+}
+
 /// Handle a new client.
-async fn handle_client(client: Async<TcpStream>, tls: Option<TlsAcceptor>) -> Result<()> {
+async fn handle_client(
+    ex: Arc<Executor<'static>>,
+    client: SmolStream,
+    addr: String,
+    tls: Option<TlsAcceptor>,
+    router: Arc<Router<AppState>>,
+) -> Result<()> {
     // Wrap it in TLS if necessary.
-    let client = match &tls {
-        None => SmolStream::Plain(client),
-        Some(tls) => {
-            // In case of HTTPS, establish a secure TLS connection.
-            SmolStream::Tls(tls.accept(client).await?)
-        }
+    let client = match (client, &tls) {
+        // In case of HTTPS, establish a secure TLS connection.
+        (SmolStream::Plain(client), Some(tls)) => SmolStream::Tls(tls.accept(client).await?),
+        (client, _) => client,
     };
 
-    // Build the server.
-    hyper::server::conn::http1::Builder::new()
-        .timer(SmolTimer::new())
-        .serve_connection(FuturesIo::new(client), service_fn(serve))
-        .await?;
+    // Build the server. It speaks HTTP/1.1 or HTTP/2 depending on the
+    // connection preface, which covers h2 negotiated through ALPN and h2c.
+    let mut builder = auto::Builder::new(SmolExecutor::new(ex));
+    builder.http1().timer(SmolTimer::new());
+    builder.http2().timer(SmolTimer::new());
+    builder
+        .serve_connection_with_upgrades(
+            FuturesIo::new(client),
+            service_fn(|req| serve(router.clone(), req, addr.clone())),
+        )
+        .await
+        .map_err(|e| anyhow!(e))?;
 
     Ok(())
 }
@@ -53,26 +199,29 @@ async fn handle_client(client: Async<TcpStream>, tls: Option<TlsAcceptor>) -> Re
 /// Listens for incoming connections and serves them.
 async fn listen(
     ex: &Arc<Executor<'static>>,
-    listener: Async<TcpListener>,
+    listener: Listener,
     tls: Option<TlsAcceptor>,
+    router: Arc<Router<AppState>>,
 ) -> Result<()> {
     // Format the full host address.
     let host = &match tls {
-        None => format!("http://{}", listener.get_ref().local_addr()?),
-        Some(_) => format!("https://{}", listener.get_ref().local_addr()?),
+        None => format!("http://{}", listener.local_addr()?),
+        Some(_) => format!("https://{}", listener.local_addr()?),
     };
-    println!("Listening on {}", host);
+    tracing::info!("Listening on {}", host);
 
     loop {
         // Wait for a new client.
-        let (client, _) = listener.accept().await?;
+        let (client, addr) = listener.accept().await?;
 
         // Spawn a task to handle this connection.
         ex.spawn({
+            let ex = ex.clone();
             let tls = tls.clone();
+            let router = router.clone();
             async move {
-                if let Err(e) = handle_client(client, tls).await {
-                    println!("Error while handling client: {}", e);
+                if let Err(e) = handle_client(ex, client, addr.clone(), tls, router).await {
+                    tracing::warn!(client = addr, error = %e, "Error while handling client");
                 }
             }
         })
@@ -80,24 +229,70 @@ async fn listen(
     }
 }
 
+/// Builds a TLS acceptor that offers HTTP/2 and HTTP/1.1 through ALPN.
+fn tls_acceptor(identity: &Path, password: &str) -> Result<TlsAcceptor> {
+    let identity = Identity::from_pkcs12(&fs::read(identity)?, password)?;
+    let acceptor = native_tls::TlsAcceptor::builder(identity)
+        .accept_alpn(&["h2", "http/1.1"])
+        .build()?;
+    Ok(TlsAcceptor::from(acceptor))
+}
+
 #[apply(main!)]
 async fn main(ex: &Arc<Executor<'static>>) -> Result<()> {
+    let settings = Settings::from_env();
+    logging::init(&settings);
+
+    // HTTPS listens on TCP too, so it can't be combined with a socket-only setup.
+    if settings.tls_identity.is_some() && !settings.tcp {
+        bail!("CCIMG_TLS_IDENTITY needs TCP, but CCIMG_TCP is disabled");
+    }
+
     // Initialize TLS with the local certificate, private key, and password.
-    // let identity = Identity::from_pkcs12(include_bytes!("identity.pfx"), "password")?;
-    // let tls = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?);
-
-    // Start HTTP and HTTPS servers.
-    let http = listen(
-        ex,
-        Async::<TcpListener>::bind(([127, 0, 0, 1], 8000))?,
-        None,
-    );
-    // let https = listen(
-    //     ex,
-    //     Async::<TcpListener>::bind(([127, 0, 0, 1], 8001))?,
-    //     Some(tls),
-    // );
-    // future::try_zip(http, https).await?;
-    http.await?;
+    let tls = match &settings.tls_identity {
+        Some(identity) => Some(tls_acceptor(identity, &settings.tls_password)?),
+        None => None,
+    };
+
+    let state = Arc::new(AppState::new(settings, ex.clone())?);
+    jobs::recover(&state)?;
+    jobs::spawn_cleanup(&state);
+    let router = Arc::new(routes(state.clone()));
+    let settings = &state.settings;
+
+    // Start HTTP, HTTPS and Unix domain socket servers.
+    let mut servers = Vec::new();
+    if settings.tcp {
+        let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 8000))?;
+        servers.push(listen(ex, Listener::Tcp(listener), None, router.clone()));
+    }
+    if let Some(tls) = tls {
+        let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 8001))?;
+        servers.push(listen(
+            ex,
+            Listener::Tcp(listener),
+            Some(tls),
+            router.clone(),
+        ));
+    }
+    if let Some(path) = &settings.unix_socket {
+        #[cfg(unix)]
+        servers.push(listen(
+            ex,
+            Listener::bind_unix(path, settings.unix_socket_mode)?,
+            None,
+            router.clone(),
+        ));
+        #[cfg(not(unix))]
+        bail!(
+            "Unix domain sockets are not supported here: {}",
+            path.display()
+        );
+    }
+    if servers.is_empty() {
+        bail!("Nothing to listen on: TCP is disabled and no Unix socket is set");
+    }
+
+    future::try_join_all(servers).await?;
     Ok(())
 }
@@ -0,0 +1,139 @@
+use anyhow::{Result, anyhow, bail};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// A JSON Patch (RFC 6902) style operation. Only `add`, `replace` and
+/// `remove` are supported, which is all config overrides need.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Replace { path: String, value: Value },
+    Remove { path: String },
+}
+
+/// Applies the operations in order to `target`.
+pub fn apply(target: &mut Value, operations: &[PatchOperation]) -> Result<()> {
+    for operation in operations {
+        match operation {
+            PatchOperation::Add { path, value } => add(target, path, value.clone())?,
+            PatchOperation::Replace { path, value } => {
+                *pointer_mut(target, path)? = value.clone();
+            }
+            PatchOperation::Remove { path } => remove(target, path)?,
+        }
+    }
+    Ok(())
+}
+
+fn add(target: &mut Value, path: &str, value: Value) -> Result<()> {
+    let (parent, key) = split_pointer(path)?;
+    match pointer_mut(target, parent)? {
+        Value::Object(map) => {
+            map.insert(key, value);
+        }
+        Value::Array(items) if key == "-" => items.push(value),
+        Value::Array(items) => {
+            let index = array_index(&key, items.len() + 1, path)?;
+            items.insert(index, value);
+        }
+        _ => bail!("Cannot add to non-container at {}", path),
+    }
+    Ok(())
+}
+
+fn remove(target: &mut Value, path: &str) -> Result<()> {
+    let (parent, key) = split_pointer(path)?;
+    match pointer_mut(target, parent)? {
+        Value::Object(map) => {
+            map.remove(&key)
+                .ok_or_else(|| anyhow!("Nothing to remove at {}", path))?;
+        }
+        Value::Array(items) => {
+            let index = array_index(&key, items.len(), path)?;
+            items.remove(index);
+        }
+        _ => bail!("Cannot remove from non-container at {}", path),
+    }
+    Ok(())
+}
+
+fn pointer_mut<'a>(target: &'a mut Value, path: &str) -> Result<&'a mut Value> {
+    target
+        .pointer_mut(path)
+        .ok_or_else(|| anyhow!("Path {} does not exist", path))
+}
+
+/// Splits a JSON pointer into its parent pointer and the unescaped last token.
+fn split_pointer(path: &str) -> Result<(&str, String)> {
+    let (parent, key) = path
+        .rsplit_once('/')
+        .ok_or_else(|| anyhow!("Invalid JSON pointer {}", path))?;
+    Ok((parent, key.replace("~1", "/").replace("~0", "~")))
+}
+
+fn array_index(key: &str, len: usize, path: &str) -> Result<usize> {
+    match key.parse::<usize>() {
+        Ok(index) if index < len => Ok(index),
+        _ => bail!("Invalid array index at {}", path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn patched(mut target: Value, operations: Value) -> Result<Value> {
+        let operations: Vec<PatchOperation> = serde_json::from_value(operations)?;
+        apply(&mut target, &operations)?;
+        Ok(target)
+    }
+
+    #[test]
+    fn adds_to_objects_and_arrays() {
+        let target = json!({ "output": {}, "operations": ["a", "c"] });
+        let result = patched(
+            target,
+            json!([
+                { "op": "add", "path": "/output/format", "value": "png" },
+                { "op": "add", "path": "/operations/1", "value": "b" },
+                { "op": "add", "path": "/operations/-", "value": "d" },
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            result,
+            json!({ "output": { "format": "png" }, "operations": ["a", "b", "c", "d"] })
+        );
+    }
+
+    #[test]
+    fn replaces_and_removes() {
+        let target = json!({ "a/b": 1, "list": [1, 2, 3], "seed": 4 });
+        let result = patched(
+            target,
+            json!([
+                { "op": "replace", "path": "/a~1b", "value": 2 },
+                { "op": "remove", "path": "/list/0" },
+                { "op": "remove", "path": "/seed" },
+            ]),
+        )
+        .unwrap();
+        assert_eq!(result, json!({ "a/b": 2, "list": [2, 3] }));
+    }
+
+    #[test]
+    fn rejects_missing_paths() {
+        let target = json!({ "list": [1] });
+        for operations in [
+            json!([{ "op": "replace", "path": "/missing", "value": 1 }]),
+            json!([{ "op": "remove", "path": "/missing" }]),
+            json!([{ "op": "remove", "path": "/list/1" }]),
+            json!([{ "op": "add", "path": "/list/2", "value": 1 }]),
+            json!([{ "op": "add", "path": "/missing/key", "value": 1 }]),
+        ] {
+            assert!(patched(target.clone(), operations).is_err());
+        }
+    }
+}
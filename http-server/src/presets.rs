@@ -0,0 +1,293 @@
+use core::config::Operation;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// A declared preset parameter.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Parameter {
+    /// Used when the request doesn't set the parameter. Parameters without a
+    /// default are required.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// A named list of operations.
+///
+/// Operations may contain `${name}` placeholders. A string that is exactly one
+/// placeholder is replaced by the parameter value as is (so numbers stay
+/// numbers), placeholders inside longer strings are replaced by its text.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preset {
+    #[serde(default)]
+    pub parameters: BTreeMap<String, Parameter>,
+    pub operations: Vec<Value>,
+}
+
+impl Preset {
+    /// Checks that every placeholder is declared and, when all parameters
+    /// have defaults, that the operations are valid.
+    pub fn validate(&self) -> Result<()> {
+        let mut used = Vec::new();
+        for operation in &self.operations {
+            placeholders(operation, &mut used);
+        }
+        if let Some(name) = used.iter().find(|n| !self.parameters.contains_key(*n)) {
+            bail!("Parameter {} is used but not declared", name);
+        }
+
+        if self.parameters.values().all(|p| p.default.is_some()) {
+            self.operations(&Map::new())?;
+        }
+        Ok(())
+    }
+
+    /// Substitutes the parameter values and parses the operations.
+    pub fn operations(&self, values: &Map<String, Value>) -> Result<Vec<Operation>> {
+        if let Some(name) = values.keys().find(|n| !self.parameters.contains_key(*n)) {
+            bail!("Unknown preset parameter {}", name);
+        }
+
+        let mut resolved = BTreeMap::new();
+        for (name, parameter) in &self.parameters {
+            let value = values
+                .get(name)
+                .or(parameter.default.as_ref())
+                .ok_or_else(|| anyhow!("Missing preset parameter {}", name))?;
+            resolved.insert(name.as_str(), value);
+        }
+
+        let operations = self
+            .operations
+            .iter()
+            .map(|operation| substitute(operation, &resolved))
+            .collect();
+        Ok(serde_json::from_value(Value::Array(operations))?)
+    }
+}
+
+/// Presets stored as `<root>/<name>.json`.
+pub struct PresetStore {
+    root: PathBuf,
+}
+
+impl PresetStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<Preset>> {
+        let path = self.path(name)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+    }
+
+    /// Validates and stores the preset. Returns `true` if it replaced an existing one.
+    pub fn put(&self, name: &str, preset: &Preset) -> Result<bool> {
+        preset.validate()?;
+
+        let path = self.path(name)?;
+        let existed = path.exists();
+        fs::create_dir_all(&self.root)?;
+        fs::write(path, serde_json::to_vec_pretty(preset)?)?;
+        Ok(existed)
+    }
+
+    /// Deletes a preset. Returns `false` if it didn't exist.
+    pub fn delete(&self, name: &str) -> Result<bool> {
+        let path = self.path(name)?;
+        if !path.exists() {
+            return Ok(false);
+        }
+        fs::remove_file(path)?;
+        Ok(true)
+    }
+
+    /// Lists preset names in alphabetical order.
+    pub fn names(&self) -> Result<Vec<String>> {
+        if !self.root.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json")
+                && let Some(stem) = path.file_stem()
+            {
+                names.push(stem.to_string_lossy().into_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Replaces `preset` and `params` in a request config with the preset's
+    /// `operations`. Configs without `preset` are left untouched.
+    pub fn expand(&self, config: &mut Value) -> Result<()> {
+        let Some(object) = config.as_object_mut() else {
+            bail!("Config must be an object");
+        };
+        let Some(name) = object.remove("preset") else {
+            return Ok(());
+        };
+        let name = name
+            .as_str()
+            .ok_or_else(|| anyhow!("Preset name must be a string"))?;
+        if object.contains_key("operations") {
+            bail!("Config can't have both preset and operations");
+        }
+
+        let values = match object.remove("params") {
+            Some(Value::Object(values)) => values,
+            None => Map::new(),
+            Some(_) => bail!("Preset params must be an object"),
+        };
+
+        let preset = self
+            .get(name)?
+            .ok_or_else(|| anyhow!("Unknown preset {}", name))?;
+        let operations = preset.operations(&values)?;
+        object.insert("operations".into(), serde_json::to_value(operations)?);
+        Ok(())
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            bail!("Invalid preset name {}", name);
+        }
+        Ok(self.root.join(format!("{}.json", name)))
+    }
+}
+
+/// Collects the parameter names used in `${name}` placeholders.
+fn placeholders(value: &Value, used: &mut Vec<String>) {
+    match value {
+        Value::String(s) => {
+            let mut rest = s.as_str();
+            while let Some(start) = rest.find("${") {
+                let Some(end) = rest[start..].find('}') else {
+                    break;
+                };
+                used.push(rest[start + 2..start + end].to_string());
+                rest = &rest[start + end + 1..];
+            }
+        }
+        Value::Array(items) => items.iter().for_each(|item| placeholders(item, used)),
+        Value::Object(map) => map.values().for_each(|item| placeholders(item, used)),
+        _ => {}
+    }
+}
+
+fn substitute(value: &Value, values: &BTreeMap<&str, &Value>) -> Value {
+    match value {
+        Value::String(s) => {
+            let whole = s
+                .strip_prefix("${")
+                .and_then(|rest| rest.strip_suffix('}'))
+                .filter(|name| !name.contains('}'));
+            if let Some(value) = whole.and_then(|name| values.get(name)) {
+                return (*value).clone();
+            }
+
+            let mut text = s.clone();
+            for (name, value) in values {
+                let replacement = match value {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                text = text.replace(&format!("${{{}}}", name), &replacement);
+            }
+            Value::String(text)
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|i| substitute(i, values)).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, item)| (key.clone(), substitute(item, values)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn preset(value: Value) -> Preset {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn values(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn whole_placeholders_keep_their_type() {
+        let operations = json!([{ "type": "filter", "name": "blur", "radius": "${radius}" }]);
+        let substituted = substitute(&operations, &BTreeMap::from([("radius", &json!(2.5))]));
+        assert_eq!(
+            substituted,
+            json!([{ "type": "filter", "name": "blur", "radius": 2.5 }])
+        );
+    }
+
+    #[test]
+    fn embedded_placeholders_become_text() {
+        let substituted = substitute(
+            &json!("Hello ${name}, ${count} new"),
+            &BTreeMap::from([("name", &json!("Ada")), ("count", &json!(3))]),
+        );
+        assert_eq!(substituted, json!("Hello Ada, 3 new"));
+    }
+
+    #[test]
+    fn applies_defaults_and_requires_the_rest() {
+        let preset = preset(json!({
+            "parameters": {
+                "radius": { "default": 1.0 },
+                "amount": {},
+            },
+            "operations": [
+                { "type": "filter", "name": "blur", "radius": "${radius}" },
+                { "type": "filter", "name": "brightness", "value": "${amount}" },
+            ],
+        }));
+
+        let operations = preset.operations(&values(json!({ "amount": 10 }))).unwrap();
+        assert_eq!(operations.len(), 2);
+        assert_eq!(operations[0].name(), "blur");
+
+        let missing = preset.operations(&Map::new()).unwrap_err();
+        assert_eq!(missing.to_string(), "Missing preset parameter amount");
+        let unknown = preset
+            .operations(&values(json!({ "amount": 1, "other": 2 })))
+            .unwrap_err();
+        assert_eq!(unknown.to_string(), "Unknown preset parameter other");
+    }
+
+    #[test]
+    fn validate_rejects_undeclared_placeholders() {
+        let preset = preset(json!({
+            "operations": [{ "type": "text", "content": "${title} by ${author}" }],
+            "parameters": { "title": {} },
+        }));
+        assert_eq!(
+            preset.validate().unwrap_err().to_string(),
+            "Parameter author is used but not declared"
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use core::cancel::CancellationToken;
+use core::config::{Operation, OutputConfig};
+use core::processor::ImageProcessor;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, bail};
+use async_tungstenite::WebSocketStream;
+use async_tungstenite::tungstenite::Message;
+use futures_util::StreamExt;
+use image::DynamicImage;
+use image::imageops::FilterType;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use smol::Timer;
+use smol::future::{self, FutureExt};
+
+use crate::patch::{self, PatchOperation};
+use crate::state::AppState;
+use crate::stream::UpgradedStream;
+
+/// Messages sent by the client as text frames. The input image itself is
+/// sent once as a binary frame.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Replaces the whole document, e.g. `{"operations": [...]}` or
+    /// `{"preset": "name", "params": {...}}`, optionally with an output `format`.
+    Config { config: Value },
+    /// Applies patch operations to the current document.
+    Patch { patch: Vec<PatchOperation> },
+}
+
+/// The state of one live-preview connection.
+struct Session {
+    /// Operations document the client edits.
+    document: Value,
+    input: Option<DynamicImage>,
+    /// Downscaled copy of the input used for previews.
+    preview_input: Option<DynamicImage>,
+    /// Whether the last edit still needs a full-resolution render.
+    needs_final: bool,
+    /// Seed used while the document doesn't set one, so random operations
+    /// don't flicker between edits.
+    seed: u64,
+}
+
+/// Runs a live-preview session.
+///
+/// Every edit is answered with a preview rendered on a downscaled copy of the
+/// input (pixel-based parameters such as text positions are not scaled). Once
+/// the client stops editing for the debounce interval, the full-resolution
+/// image is rendered and sent. Every image is preceded by a text frame
+/// describing it, including the seed it was rendered with.
+pub async fn run(state: Arc<AppState>, mut ws: WebSocketStream<UpgradedStream>) -> Result<()> {
+    let mut session = Session {
+        document: json!({ "operations": [] }),
+        input: None,
+        preview_input: None,
+        needs_final: false,
+        seed: ImageProcessor::new_seed(),
+    };
+    let debounce = Duration::from_millis(state.settings.preview_debounce_ms);
+
+    loop {
+        let idle = if session.needs_final {
+            Timer::after(debounce)
+        } else {
+            Timer::never()
+        };
+        let next = async { Some(ws.next().await) }.or(async {
+            idle.await;
+            None
+        });
+
+        let Some(message) = next.await else {
+            session.needs_final = false;
+            send_render(&state, &mut ws, &session, false).await?;
+            continue;
+        };
+
+        let mut changed = match message {
+            None => return Ok(()),
+            Some(message) => handle(&state, &mut ws, &mut session, message?).await?,
+        };
+        // Coalesce edits that arrived while we were busy.
+        while let Some(Some(message)) = future::poll_once(ws.next()).await {
+            changed |= handle(&state, &mut ws, &mut session, message?).await?;
+        }
+
+        if changed && session.input.is_some() {
+            session.needs_final = true;
+            send_render(&state, &mut ws, &session, true).await?;
+        }
+    }
+}
+
+/// Handles one client message. Returns whether a new render is needed.
+async fn handle(
+    state: &AppState,
+    ws: &mut WebSocketStream<UpgradedStream>,
+    session: &mut Session,
+    message: Message,
+) -> Result<bool> {
+    let result = match message {
+        Message::Binary(data) => {
+            let size = state.settings.preview_size;
+            let decoded = state
+                .workers
+                .run(move || {
+                    let input = image::load_from_memory(&data)?;
+                    // Small inputs are previewed as they are, never upscaled.
+                    let preview_input = if input.width() > size || input.height() > size {
+                        input.resize(size, size, FilterType::Triangle)
+                    } else {
+                        input.clone()
+                    };
+                    anyhow::Ok((input, preview_input))
+                })
+                .await;
+            match decoded {
+                Ok((input, preview_input)) => {
+                    ws.send(text(json!({
+                        "type": "ready",
+                        "width": input.width(),
+                        "height": input.height(),
+                    })))
+                    .await?;
+                    session.input = Some(input);
+                    session.preview_input = Some(preview_input);
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            }
+        }
+        Message::Text(data) => match serde_json::from_str::<ClientMessage>(&data) {
+            Ok(ClientMessage::Config { config }) => {
+                session.document = config;
+                Ok(())
+            }
+            Ok(ClientMessage::Patch { patch }) => {
+                // Only keep the patch if all of it applies.
+                let mut document = session.document.clone();
+                patch::apply(&mut document, &patch).map(|()| session.document = document)
+            }
+            Err(e) => Err(anyhow!(e)),
+        },
+        Message::Close(_) => return Ok(false),
+        _ => return Ok(false),
+    };
+
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) => {
+            send_error(ws, e).await?;
+            Ok(false)
+        }
+    }
+}
+
+/// Renders the current document and sends it, or sends the error.
+async fn send_render(
+    state: &AppState,
+    ws: &mut WebSocketStream<UpgradedStream>,
+    session: &Session,
+    preview: bool,
+) -> Result<()> {
+    let input = if preview {
+        &session.preview_input
+    } else {
+        &session.input
+    };
+    let Some(input) = input.clone() else {
+        return Ok(());
+    };
+
+    let seed = session.document["seed"].as_u64().unwrap_or(session.seed);
+    let rendered = match operations(state, &session.document) {
+        Ok((operations, output)) => {
+            let context = state.render_context(&CancellationToken::new());
+            state
+                .workers
+                .run(move || {
+                    let img = ImageProcessor::apply_operations(input, &operations, seed, &context)?;
+                    let bytes = ImageProcessor::encode_image(&img, &output)?;
+                    anyhow::Ok((img.width(), img.height(), bytes))
+                })
+                .await
+        }
+        Err(e) => Err(e),
+    };
+
+    match rendered {
+        Ok((width, height, bytes)) => {
+            let kind = if preview { "preview" } else { "final" };
+            ws.send(text(
+                json!({ "type": kind, "width": width, "height": height, "seed": seed }),
+            ))
+            .await?;
+            ws.send(Message::Binary(bytes.into())).await?;
+            Ok(())
+        }
+        Err(e) => send_error(ws, e).await,
+    }
+}
+
+/// Resolves the document to operations and an output config.
+fn operations(state: &AppState, document: &Value) -> Result<(Vec<Operation>, OutputConfig)> {
+    let mut document = document.clone();
+    if !document.is_object() {
+        bail!("Config must be an object");
+    }
+    state.presets.expand(&mut document)?;
+
+    let operations = serde_json::from_value(document["operations"].take())?;
+    let output = OutputConfig {
+        destination: "preview".into(),
+        quality: document["quality"].as_u64().map(|q| q.min(100) as u8),
+        format: Some(document["format"].as_str().unwrap_or("png").to_string()),
+    };
+    Ok((operations, output))
+}
+
+async fn send_error(ws: &mut WebSocketStream<UpgradedStream>, e: anyhow::Error) -> Result<()> {
+    ws.send(text(json!({ "type": "error", "message": e.to_string() })))
+        .await?;
+    Ok(())
+}
+
+fn text(value: Value) -> Message {
+    Message::Text(value.to_string().into())
+}
@@ -0,0 +1,503 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures_util::FutureExt;
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Method, Request, Response, StatusCode};
+use percent_encoding::percent_decode_str;
+
+/// The response type produced by every handler.
+pub type HttpResponse = Response<Full<Bytes>>;
+
+type HandlerFuture = Pin<Box<dyn Future<Output = Result<HttpResponse>> + Send>>;
+type Handler<S, B> = Box<dyn Fn(Arc<S>, Request<B>, Params) -> HandlerFuture + Send + Sync>;
+type FailureHandler = Box<dyn Fn(Failure) -> Result<HttpResponse> + Send + Sync>;
+
+/// Why a handler produced no response.
+pub enum Failure {
+    /// The handler returned an error.
+    Error(anyhow::Error),
+    /// The handler panicked; this is the panic payload.
+    Panic(Box<dyn Any + Send>),
+}
+
+/// Path parameters and query string values extracted for a request.
+#[derive(Debug, Default)]
+pub struct Params {
+    path: HashMap<String, String>,
+    query: HashMap<String, String>,
+}
+
+impl Params {
+    /// Returns a path parameter, e.g. `name` for `/api/v1/presets/{name}`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.path.get(name).map(String::as_str)
+    }
+
+    /// Returns a query string value. For repeated keys the last one wins.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(String::as_str)
+    }
+}
+
+/// One segment of a route pattern.
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+struct Route<S, B> {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Handler<S, B>,
+}
+
+impl<S, B> Route<S, B> {
+    /// Matches the route pattern against request path segments.
+    fn matches(&self, path: &[String]) -> Option<HashMap<String, String>> {
+        if self.segments.len() != path.len() {
+            return None;
+        }
+
+        let mut params = HashMap::new();
+        for (segment, value) in self.segments.iter().zip(path) {
+            match segment {
+                Segment::Literal(literal) if literal == value => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => {
+                    params.insert(name.clone(), value.clone());
+                }
+            }
+        }
+        Some(params)
+    }
+}
+
+/// CORS policy applied to every routed response.
+#[derive(Debug, Clone, Default)]
+pub struct Cors {
+    /// Allowed origins. `*` allows any origin, an empty list disables CORS.
+    pub allowed_origins: Vec<String>,
+    /// Response headers scripts on allowed origins may read, besides the
+    /// CORS-safelisted ones.
+    pub exposed_headers: Vec<String>,
+}
+
+impl Cors {
+    /// Returns the value for `Access-Control-Allow-Origin` if the origin is allowed.
+    fn allow_origin(&self, origin: &str) -> Option<HeaderValue> {
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            return Some(HeaderValue::from_static("*"));
+        }
+        if self.allowed_origins.iter().any(|o| o == origin) {
+            return HeaderValue::from_str(origin).ok();
+        }
+        None
+    }
+
+    fn apply(&self, request_headers: &HeaderMap, response: &mut HttpResponse) {
+        let Some(origin) = request_headers
+            .get(header::ORIGIN)
+            .and_then(|o| o.to_str().ok())
+        else {
+            return;
+        };
+
+        if let Some(allowed) = self.allow_origin(origin) {
+            let headers = response.headers_mut();
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+            if !self.exposed_headers.is_empty()
+                && let Ok(exposed) = HeaderValue::from_str(&self.exposed_headers.join(", "))
+            {
+                headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed);
+            }
+        }
+    }
+}
+
+/// A minimal router: path patterns with `{param}` segments, query parsing,
+/// `405 Method Not Allowed`, `HEAD` and `OPTIONS` handling.
+///
+/// Every handler receives the shared state `S` along with the request, whose
+/// body is of type `B`. A handler that fails or panics is answered through
+/// `on_failure`, so its response gets the CORS headers like any other.
+pub struct Router<S, B = Incoming> {
+    routes: Vec<Route<S, B>>,
+    cors: Cors,
+    state: Arc<S>,
+    on_failure: FailureHandler,
+}
+
+impl<S: Send + Sync + 'static, B: 'static> Router<S, B> {
+    pub fn new(state: Arc<S>, cors: Cors) -> Self {
+        Self {
+            routes: Vec::new(),
+            cors,
+            state,
+            on_failure: Box::new(|_| Ok(empty(StatusCode::INTERNAL_SERVER_ERROR))),
+        }
+    }
+
+    /// Sets how failed handlers are answered. Defaults to an empty `500`.
+    pub fn on_failure<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Failure) -> Result<HttpResponse> + Send + Sync + 'static,
+    {
+        self.on_failure = Box::new(f);
+        self
+    }
+
+    /// Registers a handler for the method and path pattern, e.g. `/api/v1/presets/{name}`.
+    pub fn route<F, Fut>(mut self, method: Method, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Arc<S>, Request<B>, Params) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<HttpResponse>> + Send + 'static,
+    {
+        let segments = split_path(pattern)
+            .into_iter()
+            .map(|segment| match segment.strip_prefix('{') {
+                Some(name) => Segment::Param(name.trim_end_matches('}').to_string()),
+                None => Segment::Literal(segment),
+            })
+            .collect();
+
+        self.routes.push(Route {
+            method,
+            segments,
+            handler: Box::new(move |state, req, params| Box::pin(handler(state, req, params))),
+        });
+        self
+    }
+
+    /// Finds the route for the request and runs its handler.
+    pub async fn dispatch(&self, req: Request<B>) -> Result<HttpResponse> {
+        let path = split_path(req.uri().path());
+        let query = parse_query(req.uri().query());
+        let request_headers = req.headers().clone();
+
+        let matched: Vec<_> = self
+            .routes
+            .iter()
+            .filter_map(|route| route.matches(&path).map(|params| (route, params)))
+            .collect();
+
+        if matched.is_empty() {
+            return Ok(self.finish(&request_headers, empty(StatusCode::NOT_FOUND)));
+        }
+
+        let method = req.method().clone();
+        let lookup = if method == Method::HEAD {
+            // Fall back to the GET handler when there is no explicit HEAD route.
+            matched
+                .iter()
+                .position(|(route, _)| route.method == Method::HEAD)
+                .or_else(|| {
+                    matched
+                        .iter()
+                        .position(|(route, _)| route.method == Method::GET)
+                })
+        } else {
+            matched.iter().position(|(route, _)| route.method == method)
+        };
+
+        let Some(index) = lookup else {
+            let allow = allowed_methods(matched.iter().map(|(route, _)| &route.method));
+            let response = if method == Method::OPTIONS {
+                self.preflight(&request_headers, &allow)
+            } else {
+                let mut response = empty(StatusCode::METHOD_NOT_ALLOWED);
+                response
+                    .headers_mut()
+                    .insert(header::ALLOW, HeaderValue::from_str(&allow)?);
+                response
+            };
+            return Ok(self.finish(&request_headers, response));
+        };
+
+        let (route, path_params) = matched.into_iter().nth(index).unwrap();
+        let params = Params {
+            path: path_params,
+            query,
+        };
+        // For HEAD hyper keeps the headers (including Content-Length) and drops the body.
+        let handled =
+            AssertUnwindSafe(async { (route.handler)(self.state.clone(), req, params).await })
+                .catch_unwind()
+                .await;
+        let response = match handled {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => (self.on_failure)(Failure::Error(e))?,
+            Err(payload) => (self.on_failure)(Failure::Panic(payload))?,
+        };
+
+        Ok(self.finish(&request_headers, response))
+    }
+
+    /// Answers an `OPTIONS` request, including CORS preflight requests.
+    fn preflight(&self, request_headers: &HeaderMap, allow: &str) -> HttpResponse {
+        let mut response = empty(StatusCode::NO_CONTENT);
+        let headers = response.headers_mut();
+        let allow = HeaderValue::from_str(allow).unwrap();
+        headers.insert(header::ALLOW, allow.clone());
+
+        let is_preflight = request_headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+        let origin = request_headers
+            .get(header::ORIGIN)
+            .and_then(|o| o.to_str().ok());
+        if let (true, Some(origin)) = (is_preflight, origin)
+            && self.cors.allow_origin(origin).is_some()
+        {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, allow);
+            if let Some(requested) = request_headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+            }
+            headers.insert(
+                header::ACCESS_CONTROL_MAX_AGE,
+                HeaderValue::from_static("86400"),
+            );
+        }
+
+        response
+    }
+
+    fn finish(&self, request_headers: &HeaderMap, mut response: HttpResponse) -> HttpResponse {
+        self.cors.apply(request_headers, &mut response);
+        response
+    }
+}
+
+/// Builds an empty response with the given status.
+pub fn empty(status: StatusCode) -> HttpResponse {
+    let mut res = Response::new(Full::new(Bytes::new()));
+    *res.status_mut() = status;
+    res
+}
+
+/// Splits a path into percent-decoded, non-empty segments.
+fn split_path(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+        .collect()
+}
+
+fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    query
+        .map(|q| form_urlencoded::parse(q.as_bytes()).into_owned().collect())
+        .unwrap_or_default()
+}
+
+/// Formats the `Allow` header value for the methods registered on a path.
+fn allowed_methods<'a>(methods: impl Iterator<Item = &'a Method>) -> String {
+    let mut allow: Vec<&str> = Vec::new();
+    for method in methods {
+        if !allow.contains(&method.as_str()) {
+            allow.push(method.as_str());
+        }
+        if method == Method::GET && !allow.contains(&"HEAD") {
+            allow.push("HEAD");
+        }
+    }
+    if !allow.contains(&"OPTIONS") {
+        allow.push("OPTIONS");
+    }
+    allow.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type TestRouter = Router<(), ()>;
+
+    /// A handler answering with its name and the `id` path parameter.
+    fn handler(
+        name: &'static str,
+    ) -> impl Fn(Arc<()>, Request<()>, Params) -> std::future::Ready<Result<HttpResponse>> {
+        move |_, _, params| {
+            let body = format!("{} {}", name, params.get("id").unwrap_or("-"));
+            std::future::ready(Ok(Response::new(Full::new(Bytes::from(body)))))
+        }
+    }
+
+    fn router(cors: Cors) -> TestRouter {
+        Router::new(Arc::new(()), cors)
+            .route(Method::GET, "/items", handler("list"))
+            .route(Method::GET, "/items/{id}", handler("get"))
+            .route(Method::DELETE, "/items/{id}", handler("delete"))
+    }
+
+    fn request(method: Method, uri: &str) -> Request<()> {
+        Request::builder().method(method).uri(uri).body(()).unwrap()
+    }
+
+    fn dispatch(router: &TestRouter, req: Request<()>) -> HttpResponse {
+        smol::block_on(router.dispatch(req)).unwrap()
+    }
+
+    fn body(response: &HttpResponse) -> String {
+        use http_body_util::BodyExt;
+        let bytes = smol::block_on(response.body().clone().collect())
+            .unwrap()
+            .to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn matches_literal_and_param_segments() {
+        let router = router(Cors::default());
+
+        let response = dispatch(&router, request(Method::GET, "/items/"));
+        assert_eq!(body(&response), "list -");
+
+        let response = dispatch(&router, request(Method::GET, "/items/a%20b"));
+        assert_eq!(body(&response), "get a b");
+
+        let response = dispatch(&router, request(Method::DELETE, "/items/7"));
+        assert_eq!(body(&response), "delete 7");
+    }
+
+    #[test]
+    fn unknown_path_is_404() {
+        let response = dispatch(&router(Cors::default()), request(Method::GET, "/items/7/x"));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn wrong_method_is_405_with_allow() {
+        let response = dispatch(&router(Cors::default()), request(Method::POST, "/items/7"));
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            response.headers()[header::ALLOW],
+            "GET, HEAD, DELETE, OPTIONS"
+        );
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        let response = dispatch(&router(Cors::default()), request(Method::HEAD, "/items/7"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(&response), "get 7");
+    }
+
+    #[test]
+    fn options_answers_preflight_for_allowed_origins() {
+        let router = router(Cors {
+            allowed_origins: vec!["https://example.com".to_string()],
+            ..Cors::default()
+        });
+        let preflight = |origin: &str| {
+            let req = Request::builder()
+                .method(Method::OPTIONS)
+                .uri("/items")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                .body(())
+                .unwrap();
+            dispatch(&router, req)
+        };
+
+        let response = preflight("https://example.com");
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "GET, HEAD, OPTIONS"
+        );
+
+        let response = preflight("https://other.example");
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(
+            !response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+    }
+
+    #[test]
+    fn exposes_headers_to_allowed_origins() {
+        let router = router(Cors {
+            allowed_origins: vec!["*".to_string()],
+            exposed_headers: vec!["x-seed".to_string(), "x-request-id".to_string()],
+        });
+        let mut req = request(Method::GET, "/items/7");
+        req.headers_mut().insert(
+            header::ORIGIN,
+            HeaderValue::from_static("https://example.com"),
+        );
+
+        let response = dispatch(&router, req);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS],
+            "x-seed, x-request-id"
+        );
+
+        let response = dispatch(&router, request(Method::GET, "/items/7"));
+        assert!(
+            !response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_EXPOSE_HEADERS)
+        );
+    }
+
+    #[test]
+    fn failed_handlers_are_answered_with_cors_headers() {
+        let router = Router::new(
+            Arc::new(()),
+            Cors {
+                allowed_origins: vec!["*".to_string()],
+                ..Cors::default()
+            },
+        )
+        .route(Method::GET, "/error", |_, _: Request<()>, _| async {
+            Err(anyhow::anyhow!("broken"))
+        })
+        .route(Method::GET, "/panic", |_, _: Request<()>, _| async {
+            panic!("boom");
+        })
+        .on_failure(|failure| {
+            let body = match failure {
+                Failure::Error(e) => e.to_string(),
+                Failure::Panic(_) => "panicked".to_string(),
+            };
+            let mut response = Response::new(Full::new(Bytes::from(body)));
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            Ok(response)
+        });
+        let get = |uri| {
+            let mut req = request(Method::GET, uri);
+            req.headers_mut().insert(
+                header::ORIGIN,
+                HeaderValue::from_static("https://example.com"),
+            );
+            dispatch(&router, req)
+        };
+
+        for (uri, expected) in [("/error", "broken"), ("/panic", "panicked")] {
+            let response = get(uri);
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(body(&response), expected);
+            assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        }
+    }
+
+    #[test]
+    fn parses_query_with_last_value_winning() {
+        let query = parse_query(Some("a=1&b=x%20y&a=2"));
+        assert_eq!(query["a"], "2");
+        assert_eq!(query["b"], "x y");
+        assert!(parse_query(None).is_empty());
+    }
+}
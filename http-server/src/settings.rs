@@ -0,0 +1,121 @@
+use std::env;
+use std::path::PathBuf;
+use std::thread;
+
+/// Server settings, read from `CCIMG_*` environment variables.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    /// Origins allowed to call the API from a browser (`CCIMG_CORS_ORIGINS`,
+    /// comma separated, `*` for any).
+    pub cors_origins: Vec<String>,
+
+    /// Number of renders allowed to run in parallel (`CCIMG_WORKERS`,
+    /// defaults to the number of CPUs).
+    pub workers: usize,
+
+    /// Longest time a render may take in milliseconds, including the wait for
+    /// a worker (`CCIMG_RENDER_TIMEOUT_MS`). Unlimited when unset.
+    pub render_timeout_ms: Option<u64>,
+
+    /// Longest time a single operation may take in milliseconds
+    /// (`CCIMG_OPERATION_TIMEOUT_MS`). Unlimited when unset.
+    pub operation_timeout_ms: Option<u64>,
+
+    /// Directory of the asset library (`CCIMG_ASSETS_DIR`, defaults to `data/assets`).
+    pub assets_dir: PathBuf,
+
+    /// Directory of stored presets (`CCIMG_PRESETS_DIR`, defaults to `data/presets`).
+    pub presets_dir: PathBuf,
+
+    /// SQLite database of async jobs (`CCIMG_JOBS_DB`, defaults to `data/jobs.sqlite3`).
+    pub jobs_db: PathBuf,
+
+    /// How long finished jobs and their results are kept, in hours
+    /// (`CCIMG_JOB_RETENTION_HOURS`). Kept forever when unset.
+    pub job_retention_hours: Option<u64>,
+
+    /// Secret for signing job callbacks (`CCIMG_WEBHOOK_SECRET`). Callbacks
+    /// are sent unsigned when unset.
+    pub webhook_secret: Option<String>,
+
+    /// How many times a job callback is attempted (`CCIMG_WEBHOOK_ATTEMPTS`, defaults to 5).
+    pub webhook_attempts: u32,
+
+    /// Longest time a single callback attempt may take in milliseconds,
+    /// from connecting to reading the response (`CCIMG_WEBHOOK_TIMEOUT_MS`,
+    /// defaults to 10000).
+    pub webhook_timeout_ms: u64,
+
+    /// Serve HTTP on TCP port 8000 (`CCIMG_TCP`, defaults to true). Disable it
+    /// to only listen on the Unix domain socket; HTTPS needs it enabled.
+    pub tcp: bool,
+
+    /// Path of a Unix domain socket to serve HTTP on as well (`CCIMG_UNIX_SOCKET`).
+    pub unix_socket: Option<PathBuf>,
+
+    /// Octal permissions of the Unix domain socket, e.g. `660`
+    /// (`CCIMG_UNIX_SOCKET_MODE`). The umask applies when unset.
+    pub unix_socket_mode: Option<u32>,
+
+    /// PKCS #12 certificate and key for HTTPS on port 8001 (`CCIMG_TLS_IDENTITY`).
+    /// HTTPS is disabled when unset. Setting it with TCP disabled is an error.
+    pub tls_identity: Option<PathBuf>,
+
+    /// Password of the TLS identity (`CCIMG_TLS_PASSWORD`).
+    pub tls_password: String,
+
+    /// Longest side of live-preview frames (`CCIMG_PREVIEW_SIZE`, defaults to 512).
+    pub preview_size: u32,
+
+    /// How long a live-preview client must stop editing before the
+    /// full-resolution render (`CCIMG_PREVIEW_DEBOUNCE_MS`, defaults to 500).
+    pub preview_debounce_ms: u64,
+
+    /// Write logs as JSON lines (`CCIMG_LOG_FORMAT=json`) instead of text.
+    pub log_json: bool,
+}
+
+impl Settings {
+    pub fn from_env() -> Self {
+        Self {
+            cors_origins: list("CCIMG_CORS_ORIGINS"),
+            workers: parse("CCIMG_WORKERS")
+                .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get())),
+            render_timeout_ms: parse("CCIMG_RENDER_TIMEOUT_MS"),
+            operation_timeout_ms: parse("CCIMG_OPERATION_TIMEOUT_MS"),
+            assets_dir: parse("CCIMG_ASSETS_DIR").unwrap_or_else(|| "data/assets".into()),
+            presets_dir: parse("CCIMG_PRESETS_DIR").unwrap_or_else(|| "data/presets".into()),
+            jobs_db: parse("CCIMG_JOBS_DB").unwrap_or_else(|| "data/jobs.sqlite3".into()),
+            job_retention_hours: parse("CCIMG_JOB_RETENTION_HOURS"),
+            webhook_secret: env::var("CCIMG_WEBHOOK_SECRET").ok(),
+            webhook_attempts: parse("CCIMG_WEBHOOK_ATTEMPTS").unwrap_or(5),
+            webhook_timeout_ms: parse("CCIMG_WEBHOOK_TIMEOUT_MS").unwrap_or(10_000),
+            tcp: parse("CCIMG_TCP").unwrap_or(true),
+            unix_socket: parse("CCIMG_UNIX_SOCKET"),
+            unix_socket_mode: env::var("CCIMG_UNIX_SOCKET_MODE")
+                .ok()
+                .and_then(|mode| u32::from_str_radix(mode.trim(), 8).ok()),
+            tls_identity: parse("CCIMG_TLS_IDENTITY"),
+            tls_password: env::var("CCIMG_TLS_PASSWORD").unwrap_or_default(),
+            preview_size: parse("CCIMG_PREVIEW_SIZE").unwrap_or(512),
+            preview_debounce_ms: parse("CCIMG_PREVIEW_DEBOUNCE_MS").unwrap_or(500),
+            log_json: env::var("CCIMG_LOG_FORMAT").is_ok_and(|f| f.eq_ignore_ascii_case("json")),
+        }
+    }
+}
+
+/// Reads a comma separated list, skipping empty items.
+fn list(name: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Parses a variable, ignoring it when unset or invalid.
+fn parse<T: std::str::FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok()?.trim().parse().ok()
+}
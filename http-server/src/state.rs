@@ -0,0 +1,64 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use core::cancel::CancellationToken;
+use core::processor::RenderContext;
+use core::storage::DefaultStorage;
+
+use anyhow::Result;
+use smol::Executor;
+
+use crate::assets::AssetLibrary;
+use crate::jobs::Jobs;
+use crate::presets::PresetStore;
+use crate::settings::Settings;
+use crate::workers::Workers;
+
+/// State shared by all request handlers.
+pub struct AppState {
+    pub settings: Settings,
+    pub workers: Workers,
+    pub assets: Arc<AssetLibrary>,
+    pub presets: PresetStore,
+    pub jobs: Jobs,
+    /// Asset lookup (through the library) and storage used by every render.
+    /// Use `render_context` to get one with the configured timeouts.
+    pub context: RenderContext,
+    /// Executor for background work such as async jobs.
+    pub executor: Arc<Executor<'static>>,
+}
+
+impl AppState {
+    pub fn new(settings: Settings, executor: Arc<Executor<'static>>) -> Result<Self> {
+        let assets = Arc::new(AssetLibrary::new(&settings.assets_dir));
+        Ok(Self {
+            workers: Workers::new(settings.workers),
+            context: RenderContext {
+                assets: assets.clone(),
+                storage: Arc::new(DefaultStorage::from_env()),
+                cancel: CancellationToken::new(),
+                operation_timeout: None,
+            },
+            assets,
+            presets: PresetStore::new(&settings.presets_dir),
+            jobs: Jobs::open(&settings.jobs_db)?,
+            executor,
+            settings,
+        })
+    }
+
+    /// Returns the render context stopped by `cancel`, with the configured
+    /// timeouts starting now.
+    pub fn render_context(&self, cancel: &CancellationToken) -> RenderContext {
+        let mut context = self.context.clone();
+        context.cancel = match self.settings.render_timeout_ms {
+            Some(ms) => cancel.with_timeout(Duration::from_millis(ms)),
+            None => cancel.clone(),
+        };
+        context.operation_timeout = self
+            .settings
+            .operation_timeout_ms
+            .map(Duration::from_millis);
+        context
+    }
+}
@@ -1,17 +1,84 @@
-use std::net::TcpStream;
+#[cfg(unix)]
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use async_native_tls::TlsStream;
-use smol::{io, prelude::*, Async};
+use hyper::rt::ReadBuf;
+use hyper::upgrade::Upgraded;
+use smol::{Async, io, prelude::*};
 
-/// A TCP or TCP+TLS connection.
+/// A TCP, TCP+TLS or Unix domain socket connection.
 pub enum SmolStream {
     /// A plain TCP connection.
     Plain(Async<TcpStream>),
 
     /// A TCP connection secured by TLS.
     Tls(TlsStream<Async<TcpStream>>),
+
+    /// A Unix domain socket connection.
+    #[cfg(unix)]
+    Unix(Async<UnixStream>),
+}
+
+/// A TCP or Unix domain socket listener.
+pub enum Listener {
+    Tcp(Async<TcpListener>),
+
+    #[cfg(unix)]
+    Unix(Async<UnixListener>),
+}
+
+impl Listener {
+    /// Binds a Unix domain socket, replacing a stale socket file left by a
+    /// previous run, and sets its permissions (e.g. `0o660`) if given.
+    #[cfg(unix)]
+    pub fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<Self> {
+        if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let listener = Async::<UnixListener>::bind(path)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(Self::Unix(listener))
+    }
+
+    /// Describes the local address, e.g. `127.0.0.1:8000` or `unix:/run/ccimg.sock`.
+    pub fn local_addr(&self) -> io::Result<String> {
+        match self {
+            Self::Tcp(l) => Ok(l.get_ref().local_addr()?.to_string()),
+            #[cfg(unix)]
+            Self::Unix(l) => {
+                let addr = l.get_ref().local_addr()?;
+                let path = addr.as_pathname().unwrap_or(Path::new(""));
+                Ok(format!("unix:{}", path.display()))
+            }
+        }
+    }
+
+    /// Waits for a new client. Returns the connection and the client address
+    /// (`unix` for Unix domain socket peers).
+    pub async fn accept(&self) -> io::Result<(SmolStream, String)> {
+        match self {
+            Self::Tcp(l) => {
+                let (stream, addr) = l.accept().await?;
+                Ok((SmolStream::Plain(stream), addr.to_string()))
+            }
+            #[cfg(unix)]
+            Self::Unix(l) => {
+                let (stream, _) = l.accept().await?;
+                Ok((SmolStream::Unix(stream), "unix".to_string()))
+            }
+        }
+    }
 }
 
 impl AsyncRead for SmolStream {
@@ -23,6 +90,8 @@ impl AsyncRead for SmolStream {
         match &mut *self {
             Self::Plain(s) => Pin::new(s).poll_read(cx, buf),
             Self::Tls(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(s) => Pin::new(s).poll_read(cx, buf),
         }
     }
 }
@@ -36,6 +105,8 @@ impl AsyncWrite for SmolStream {
         match &mut *self {
             Self::Plain(s) => Pin::new(s).poll_write(cx, buf),
             Self::Tls(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(s) => Pin::new(s).poll_write(cx, buf),
         }
     }
 
@@ -43,13 +114,54 @@ impl AsyncWrite for SmolStream {
         match &mut *self {
             Self::Plain(s) => Pin::new(s).poll_close(cx),
             Self::Tls(s) => Pin::new(s).poll_close(cx),
+            #[cfg(unix)]
+            Self::Unix(s) => Pin::new(s).poll_close(cx),
         }
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         match &mut *self {
-            Self::Plain(s) => Pin::new(s).poll_close(cx),
-            Self::Tls(s) => Pin::new(s).poll_close(cx),
+            Self::Plain(s) => Pin::new(s).poll_flush(cx),
+            Self::Tls(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(s) => Pin::new(s).poll_flush(cx),
         }
     }
 }
+
+/// An upgraded HTTP connection (e.g. a WebSocket) exposed through the
+/// `futures-io` traits.
+pub struct UpgradedStream(pub Upgraded);
+
+impl AsyncRead for UpgradedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut read_buf = ReadBuf::new(buf);
+        match hyper::rt::Read::poll_read(Pin::new(&mut self.0), cx, read_buf.unfilled()) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(read_buf.filled().len())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for UpgradedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        hyper::rt::Write::poll_write(Pin::new(&mut self.0), cx, buf)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        hyper::rt::Write::poll_shutdown(Pin::new(&mut self.0), cx)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        hyper::rt::Write::poll_flush(Pin::new(&mut self.0), cx)
+    }
+}
@@ -0,0 +1,268 @@
+use std::net::TcpStream;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use async_native_tls::TlsConnector;
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderValue};
+use hyper::{Method, Request, Uri};
+use serde::Serialize;
+use sha2::Sha256;
+use smol::{Async, Timer, future};
+use smol_hyper::rt::FuturesIo;
+
+use crate::settings::Settings;
+use crate::stream::SmolStream;
+
+/// Header carrying `sha256=<hex HMAC of the body>` when a secret is configured.
+pub const SIGNATURE_HEADER: &str = "x-ccimg-signature";
+
+/// Parses a callback URL, which must be `http` or `https` with a host.
+pub fn parse_url(url: &str) -> Result<Uri> {
+    let uri: Uri = url.parse()?;
+    if !matches!(uri.scheme_str(), Some("http" | "https")) {
+        bail!("Callback URL must be http or https");
+    }
+    if uri.host().is_none_or(str::is_empty) {
+        bail!("Callback URL has no host");
+    }
+    Ok(uri)
+}
+
+/// POSTs `payload` as JSON to `url`, retrying with exponential backoff until
+/// the receiver answers with a 2xx status. An attempt that takes longer than
+/// the configured timeout counts as failed.
+pub async fn deliver<T: Serialize>(settings: &Settings, url: &str, payload: &T) -> Result<()> {
+    let uri = parse_url(url)?;
+    let body = serde_json::to_vec(payload)?;
+    let signature = settings
+        .webhook_secret
+        .as_deref()
+        .map(|secret| sign(secret, &body))
+        .transpose()?;
+
+    let timeout = Duration::from_millis(settings.webhook_timeout_ms);
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 1;
+    loop {
+        let timed_out = async {
+            Timer::after(timeout).await;
+            Err(anyhow!("Timed out after {} ms", timeout.as_millis()))
+        };
+        match future::or(post(&uri, &body, signature.as_deref()), timed_out).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= settings.webhook_attempts => {
+                return Err(e.context(format!("Gave up after {} attempts", attempt)));
+            }
+            Err(e) => {
+                tracing::warn!(url, attempt, error = %e, "Callback attempt failed");
+                Timer::after(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+async fn post(uri: &Uri, body: &[u8], signature: Option<&str>) -> Result<()> {
+    // Both were checked by `parse_url`.
+    let host = uri.host().unwrap_or_default();
+    let tls = uri.scheme_str() == Some("https");
+    let default_port = if tls { 443 } else { 80 };
+    let port = uri.port_u16().unwrap_or(default_port);
+    let host_header = if port == default_port {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, port)
+    };
+
+    // Try every address the host resolves to, e.g. both IPv6 and IPv4.
+    let mut tcp = Err(anyhow!("Could not resolve {}", host));
+    for address in smol::net::resolve((host, port)).await? {
+        tcp = Async::<TcpStream>::connect(address)
+            .await
+            .with_context(|| format!("Could not connect to {}", address));
+        if tcp.is_ok() {
+            break;
+        }
+    }
+    let tcp = tcp?;
+    let stream = if tls {
+        SmolStream::Tls(TlsConnector::new().connect(host, tcp).await?)
+    } else {
+        SmolStream::Plain(tcp)
+    };
+
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(FuturesIo::new(stream)).await?;
+
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    let mut req = Request::builder()
+        .method(Method::POST)
+        .uri(path)
+        .header(header::HOST, host_header)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::copy_from_slice(body)))?;
+    if let Some(signature) = signature {
+        req.headers_mut()
+            .insert(SIGNATURE_HEADER, HeaderValue::from_str(signature)?);
+    }
+
+    // Drive the connection alongside the request; it finishes once the
+    // sender and the response are dropped.
+    let send = async move {
+        let status = sender.send_request(req).await?.status();
+        drop(sender);
+        anyhow::Ok(status)
+    };
+    let (status, _) = future::zip(send, connection).await;
+    let status = status.context("Request failed")?;
+
+    if !status.is_success() {
+        bail!("Receiver answered {}", status);
+    }
+    Ok(())
+}
+
+/// Computes the signature header value for a body.
+fn sign(secret: &str, body: &[u8]) -> Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(format!("sha256={}", hex))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// A request as seen by the receiver.
+    struct Received {
+        host: String,
+        path: String,
+        signature: Option<String>,
+        body: Vec<u8>,
+    }
+
+    /// Answers one request per status, in order, then returns what it received.
+    fn receiver(statuses: &'static [u16]) -> (String, thread::JoinHandle<Vec<Received>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook?job=1", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            statuses
+                .iter()
+                .map(|status| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let path = line.split_whitespace().nth(1).unwrap().to_string();
+
+                    let (mut host, mut signature, mut length) = (String::new(), None, 0);
+                    loop {
+                        line.clear();
+                        reader.read_line(&mut line).unwrap();
+                        let Some((name, value)) = line.trim_end().split_once(": ") else {
+                            break;
+                        };
+                        match name.to_ascii_lowercase().as_str() {
+                            "host" => host = value.to_string(),
+                            "content-length" => length = value.parse().unwrap(),
+                            SIGNATURE_HEADER => signature = Some(value.to_string()),
+                            _ => {}
+                        }
+                    }
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+
+                    write!(
+                        stream,
+                        "HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                        status
+                    )
+                    .unwrap();
+                    Received {
+                        host,
+                        path,
+                        signature,
+                        body,
+                    }
+                })
+                .collect()
+        });
+        (url, handle)
+    }
+
+    fn settings(attempts: u32, timeout_ms: u64) -> Settings {
+        Settings {
+            webhook_secret: Some("secret".to_string()),
+            webhook_attempts: attempts,
+            webhook_timeout_ms: timeout_ms,
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn accepts_only_http_callback_urls() {
+        assert!(parse_url("http://example.com/hook").is_ok());
+        assert!(parse_url("https://127.0.0.1:8443/hook?job=1").is_ok());
+        for url in [
+            "foo",
+            "/hook",
+            "ftp://example.com/",
+            "http://",
+            "mailto:a@b.c",
+            "",
+        ] {
+            assert!(parse_url(url).is_err(), "{:?}", url);
+        }
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        // RFC 4231-style check against a well-known HMAC-SHA256 value.
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog").unwrap(),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn retries_until_the_receiver_accepts() {
+        let (url, receiver) = receiver(&[500, 200]);
+        let payload = serde_json::json!({ "id": "job", "status": "done" });
+
+        smol::block_on(deliver(&settings(3, 5000), &url, &payload)).unwrap();
+
+        let received = receiver.join().unwrap();
+        assert_eq!(received.len(), 2);
+        let port = url.split(':').nth(2).unwrap().split('/').next().unwrap();
+        for request in &received {
+            assert_eq!(request.host, format!("127.0.0.1:{}", port));
+            assert_eq!(request.path, "/hook?job=1");
+            assert_eq!(request.body, serde_json::to_vec(&payload).unwrap());
+            let expected = sign("secret", &request.body).unwrap();
+            assert_eq!(request.signature.as_deref(), Some(expected.as_str()));
+        }
+    }
+
+    #[test]
+    fn gives_up_on_a_silent_receiver() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            // Accept the connection but never answer.
+            let _connection = listener.accept().unwrap();
+            thread::sleep(Duration::from_secs(5));
+        });
+
+        let error = smol::block_on(deliver(&settings(1, 200), &url, &"payload")).unwrap_err();
+        assert!(format!("{:#}", error).contains("Timed out after 200 ms"));
+    }
+}
@@ -0,0 +1,82 @@
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+use anyhow::Result;
+use rand::Rng;
+use smol::lock::Semaphore;
+use tracing::Span;
+
+/// A pool for CPU-bound rendering work.
+///
+/// Jobs run on smol's blocking thread pool so they never stall the executor,
+/// and at most `size` of them run at the same time. They run inside the
+/// caller's tracing span, so core logs carry the request ID.
+pub struct Workers {
+    permits: Semaphore,
+}
+
+impl Workers {
+    pub fn new(size: usize) -> Self {
+        Self {
+            permits: Semaphore::new(size.max(1)),
+        }
+    }
+
+    /// Waits for a free worker and runs the closure on it. A panic in the
+    /// closure is caught and returned as a [`Panicked`] error.
+    pub async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        let _permit = self.permits.acquire().await;
+        let span = Span::current();
+        smol::unblock(move || {
+            span.in_scope(|| {
+                panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+                    let panicked = Panicked::new();
+                    tracing::error!(
+                        correlation_id = %panicked.correlation_id,
+                        panic = panic_message(&*payload),
+                        "Render panicked"
+                    );
+                    Err(panicked.into())
+                })
+            })
+        })
+        .await
+    }
+}
+
+/// A job that panicked. The panic is logged with the correlation ID, which is
+/// also reported to the client so the two can be matched up.
+#[derive(Debug)]
+pub struct Panicked {
+    pub correlation_id: String,
+}
+
+impl Panicked {
+    pub fn new() -> Self {
+        Self {
+            correlation_id: format!("{:016x}", rand::thread_rng().r#gen::<u64>()),
+        }
+    }
+}
+
+impl fmt::Display for Panicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Internal error (correlation ID {})", self.correlation_id)
+    }
+}
+
+impl std::error::Error for Panicked {}
+
+/// Extracts the message of a panic payload.
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Unknown panic")
+}